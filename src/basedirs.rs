@@ -0,0 +1,177 @@
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::path::PathBuf;
+
+use crate::linemarker;
+
+// Base directories of build trees.
+//
+// Paths below base directories are replaced by relative ones in data used to compute
+// cache keys, so the same sources checked out to different locations share cache entries.
+// Compiler still receives original paths.
+#[derive(Default)]
+pub struct BaseDirs {
+    // Normalized directories (see `normalize`) without trailing separator.
+    dirs: Vec<String>,
+}
+
+impl BaseDirs {
+    #[must_use]
+    pub fn new(dirs: &[PathBuf]) -> Self {
+        BaseDirs {
+            dirs: dirs
+                .iter()
+                .filter_map(|dir| dir.to_str())
+                .map(|dir| normalize(dir).trim_end_matches('/').to_string())
+                .filter(|dir| !dir.is_empty())
+                .collect(),
+        }
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.dirs.is_empty()
+    }
+
+    // Replace base directories in line markers of preprocessed output.
+    #[must_use]
+    pub fn rewrite_preprocessed<'a>(&self, preprocessed: &'a [u8]) -> Cow<'a, [u8]> {
+        if self.is_empty() {
+            return Cow::Borrowed(preprocessed);
+        }
+        linemarker::rewrite(preprocessed, |name| self.rewrite(name))
+    }
+
+    // Replace base directories in compiler argument.
+    #[must_use]
+    pub fn rewrite_arg<'a>(&self, arg: &'a OsStr) -> Cow<'a, OsStr> {
+        match arg.to_str().and_then(|arg| self.rewrite(arg)) {
+            Some(rewritten) => Cow::Owned(OsString::from(rewritten)),
+            None => Cow::Borrowed(arg),
+        }
+    }
+
+    // Replace all occurrences of base directories by `.`, returns None if nothing is replaced.
+    fn rewrite(&self, value: &str) -> Option<String> {
+        let normalized = normalize(value);
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        for dir in &self.dirs {
+            for (start, _) in normalized.match_indices(dir.as_str()) {
+                let end = start + dir.len();
+                let boundary = is_start_boundary(&normalized[..start], dir)
+                    && normalized[end..]
+                        .chars()
+                        .next()
+                        .is_none_or(|c| c == '/' || !is_name_char(c));
+                if boundary && !ranges.iter().any(|&(s, e)| start < e && s < end) {
+                    ranges.push((start, end));
+                }
+            }
+        }
+        if ranges.is_empty() {
+            return None;
+        }
+        ranges.sort_unstable();
+        let mut result = String::with_capacity(value.len());
+        let mut last = 0;
+        for (start, end) in ranges {
+            result.push_str(&value[last..start]);
+            result.push('.');
+            last = end;
+        }
+        result.push_str(&value[last..]);
+        Some(result)
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-' || c == '.'
+}
+
+// Check that base directory match is not a tail of other path: it should follow start of
+// string, separator or other delimiter, or end of option prefix like `-I`.
+fn is_start_boundary(prefix: &str, dir: &str) -> bool {
+    let head = prefix.trim_end_matches(is_name_char);
+    let word = &prefix[head.len()..];
+    if word.is_empty() || word.starts_with('-') {
+        return true;
+    }
+    // MSVC option like `/I`, only Windows base directories can follow it.
+    match head.strip_suffix('/') {
+        Some(head) => {
+            !dir.starts_with('/') && head.chars().next_back().is_none_or(char::is_whitespace)
+        }
+        None => false,
+    }
+}
+
+// Use the same separator everywhere, Windows paths are also case-insensitive.
+// Keeps byte offsets of the original string.
+fn normalize(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    if cfg!(windows) {
+        path.to_ascii_lowercase()
+    } else {
+        path
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::path::PathBuf;
+
+    use crate::basedirs::BaseDirs;
+
+    #[test]
+    fn test_rewrite() {
+        let base_dirs = BaseDirs::new(&[PathBuf::from("/home/alice/work/")]);
+        assert_eq!(
+            base_dirs.rewrite_arg(OsStr::new("-I/home/alice/work/include")),
+            OsStr::new("-I./include")
+        );
+        assert_eq!(
+            base_dirs.rewrite_arg(OsStr::new("-I/home/alice/work")),
+            OsStr::new("-I.")
+        );
+        assert_eq!(
+            base_dirs.rewrite_arg(OsStr::new("-I/home/alice/work2/include")),
+            OsStr::new("-I/home/alice/work2/include")
+        );
+        assert_eq!(
+            base_dirs
+                .rewrite_preprocessed(
+                    b"# 1 \"/home/alice/work/main.cpp\"\n# 1 \"/usr/include/stdio.h\" 1 3\n"
+                )
+                .as_ref(),
+            b"# 1 \"./main.cpp\"\n# 1 \"/usr/include/stdio.h\" 1 3\n"
+        );
+    }
+
+    #[test]
+    fn test_rewrite_boundary() {
+        let base_dirs = BaseDirs::new(&[PathBuf::from("/home/alice")]);
+        assert_eq!(
+            base_dirs.rewrite_arg(OsStr::new("/opt/home/alice/work/x")),
+            OsStr::new("/opt/home/alice/work/x")
+        );
+        assert_eq!(
+            base_dirs.rewrite_arg(OsStr::new("-I/opt/home/alice/work/x")),
+            OsStr::new("-I/opt/home/alice/work/x")
+        );
+        assert_eq!(
+            base_dirs.rewrite_arg(OsStr::new("-isystem/home/alice/x")),
+            OsStr::new("-isystem./x")
+        );
+        assert_eq!(
+            base_dirs.rewrite_arg(OsStr::new("--sysroot=/home/alice")),
+            OsStr::new("--sysroot=.")
+        );
+
+        let base_dirs = BaseDirs::new(&[PathBuf::from("c:/work")]);
+        assert_eq!(
+            base_dirs.rewrite_arg(OsStr::new("/Ic:/work/include")),
+            OsStr::new("/I./include")
+        );
+    }
+}
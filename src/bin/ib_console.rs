@@ -13,7 +13,9 @@ use petgraph::{EdgeDirection, Graph};
 use octobuild::cluster::client::RemoteCompiler;
 use octobuild::compiler::{CommandArgs, Compiler, SharedState};
 use octobuild::config::Config;
+use octobuild::io::statistic::{Statistic, StatisticFile};
 use octobuild::simple::supported_compilers;
+use octobuild::utils::format_age;
 use octobuild::version;
 use octobuild::worker::execute_graph;
 use octobuild::worker::validate_graph;
@@ -45,6 +47,17 @@ pub fn main() -> octobuild::Result<()> {
 }
 
 fn execute(config: &Config, args: &[String]) -> octobuild::Result<()> {
+    let mut config = config.clone();
+    let mut args: Vec<&String> = args.iter().collect();
+    args.retain(|arg| {
+        if arg.eq_ignore_ascii_case("/keepgoing") {
+            config.keep_going = true;
+            return false;
+        }
+        true
+    });
+    let config = &config;
+
     let state = SharedState::new(config)?;
     let compiler = RemoteCompiler::new(&config.coordinator, supported_compilers());
 
@@ -56,16 +69,38 @@ fn execute(config: &Config, args: &[String]) -> octobuild::Result<()> {
                 _ = std::fs::remove_dir_all(&config.cache);
                 println!("Done!");
                 Ok(())
+            } else if arg.eq_ignore_ascii_case("/stats") {
+                let (statistic, since) = StatisticFile::new(&config.cache).load()?;
+                println!(
+                    "{statistic} (since {} ago)",
+                    format_age(since.elapsed().unwrap_or_default().as_secs())
+                );
+                Ok(())
+            } else if arg.eq_ignore_ascii_case("/zerostats") {
+                StatisticFile::new(&config.cache).reset()?;
+                println!("Cache statistic is reset");
+                Ok(())
             } else {
                 let mut graph = Graph::new();
-                let file = File::open(Path::new(&args[0]))?;
+                let file = File::open(Path::new(arg))?;
                 xg::parser::parse(&mut graph, BufReader::new(file))?;
-                let build_graph = prepare_graph(&compiler, validate_graph(graph)?, config)?;
+                let build_graph =
+                    prepare_graph(&compiler, validate_graph(graph)?, config, &state.statistic)?;
 
-                let result =
-                    execute_graph(&state, build_graph, config.process_limit, print_task_result);
+                let result = execute_graph(
+                    &state,
+                    build_graph,
+                    config.process_limit,
+                    config.keep_going,
+                    print_task_result,
+                );
+                state.cache.flush();
                 drop(state.cache.cleanup());
+                state.save_statistic();
                 println!("{}", state.statistic);
+                if let Err(octobuild::Error::BuildFailed { failed, skipped }) = &result {
+                    print_failures(failed, skipped);
+                }
                 result
             }
         }
@@ -80,6 +115,7 @@ fn prepare_graph<C: Compiler>(
     compiler: &C,
     graph: XgGraph,
     config: &Config,
+    statistic: &Statistic,
 ) -> octobuild::Result<BuildGraph> {
     let mut remap: Vec<NodeIndex> = Vec::with_capacity(graph.node_count());
     let mut depends: Vec<NodeIndex> = Vec::with_capacity(graph.node_count());
@@ -96,6 +132,7 @@ fn prepare_graph<C: Compiler>(
             CommandArgs::Raw(raw_args),
             &node.title,
             config.run_second_cpp,
+            statistic,
         );
         let node_index = NodeIndex::new(remap.len());
         if actions.len() == 1 {
@@ -153,6 +190,19 @@ fn print_task_result(result: &BuildResult) -> octobuild::Result<()> {
     Ok(())
 }
 
+fn print_failures(failed: &[String], skipped: &[String]) {
+    println!("Failed tasks:");
+    for title in failed {
+        println!("  {title}");
+    }
+    if !skipped.is_empty() {
+        println!("Skipped tasks (depend on failed ones):");
+        for title in skipped {
+            println!("  {title}");
+        }
+    }
+}
+
 fn expand_arg<F: Fn(&str) -> Option<String>>(arg: &str, resolver: &F) -> String {
     let mut result = String::new();
     let mut suffix = arg;
@@ -0,0 +1,391 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::process;
+use std::time::SystemTime;
+
+use clap::{Parser, Subcommand};
+use path_absolutize::Absolutize;
+
+use octobuild::cache::{CacheBackend, EntryMeta};
+use octobuild::config::Config;
+use octobuild::direct::Manifest;
+use octobuild::explain::ExplainLog;
+use octobuild::io::bundle::{import_bundle, BundleWriter};
+use octobuild::io::cacheindex::{unix_time, IndexEntries, IndexEntry};
+use octobuild::io::filecache::{inspect_cache, EntryFormat, FileCache};
+use octobuild::io::statistic::StatisticFile;
+use octobuild::utils::format_age;
+use octobuild::version;
+
+/// Local cache administration tool.
+#[derive(Parser)]
+#[command(version = version::VERSION)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show cache summary and cumulative statistic.
+    Stats {
+        /// Reset cumulative statistic.
+        #[arg(long)]
+        zero: bool,
+    },
+    /// List cache entries, most recently used first.
+    List,
+    /// Show content of cache entry.
+    Inspect { hash: String },
+    /// Check integrity of all cache entries.
+    Verify {
+        /// Remove damaged entries.
+        #[arg(long)]
+        remove: bool,
+    },
+    /// Remove cache entries.
+    Evict {
+        /// Remove entries not used for given time (for example: 30m, 12h, 7d).
+        #[arg(long, value_parser = parse_duration)]
+        older_than: Option<u64>,
+        /// Remove least recently used entries above given size (for example: 500M, 10G).
+        #[arg(long, value_parser = parse_size)]
+        max_size: Option<u64>,
+    },
+    /// Remove all cache entries.
+    Clear,
+    /// Explain why latest compilation of source file did not reuse previous cache key (requires `explain` setting).
+    Explain { source: PathBuf },
+    /// Write cache entries into bundle file.
+    Export {
+        bundle: PathBuf,
+        /// Export only entries used within given time (for example: 30m, 12h, 7d).
+        #[arg(long, value_parser = parse_duration)]
+        since: Option<u64>,
+        /// Export only entries of given toolchain.
+        #[arg(long)]
+        toolchain: Option<String>,
+        /// Export only entries listed in file (one hash per line).
+        #[arg(long)]
+        hash_list: Option<PathBuf>,
+    },
+    /// Verify entries of bundle file and add them to cache.
+    Import { bundle: PathBuf },
+}
+
+fn main() {
+    let args = Args::parse();
+    process::exit(match execute(args.command) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("ERROR: {e}");
+            1
+        }
+    })
+}
+
+fn execute(command: Command) -> octobuild::Result<()> {
+    let config = Config::load()?;
+    let cache = FileCache::new(&config);
+    match command {
+        Command::Stats { zero } => {
+            let statistic_file = StatisticFile::new(&config.cache);
+            if zero {
+                statistic_file.reset()?;
+            }
+            let (statistic, since) = statistic_file.load()?;
+            let entries = cache.entries()?;
+            let mut toolchains: BTreeMap<&str, (usize, u64)> = BTreeMap::new();
+            let mut blobs: HashMap<&str, u64> = HashMap::new();
+            for entry in entries.values() {
+                let toolchain = toolchains
+                    .entry(entry.toolchain.as_deref().unwrap_or("unknown"))
+                    .or_default();
+                toolchain.0 += 1;
+                toolchain.1 += entry_size(entry);
+                blobs.extend(
+                    entry
+                        .blobs
+                        .iter()
+                        .map(|(blob, size)| (blob.as_str(), *size)),
+                );
+            }
+            println!("Cache directory: {}", cache.dir().display());
+            println!(
+                "Entries: {}, blobs: {}, size: {} of {}",
+                entries.len(),
+                blobs.len(),
+                format_size(cache_size(&entries)),
+                format_size(cache.limit()),
+            );
+            for (toolchain, (count, size)) in toolchains {
+                println!("  {toolchain}: {count} entries, {}", format_size(size));
+            }
+            let namespaces = FileCache::namespaces(&config.cache)?;
+            if !namespaces.is_empty() {
+                println!("Namespaces:");
+                for namespace in std::iter::once(None).chain(namespaces.into_iter().map(Some)) {
+                    let cache = FileCache::new(&Config {
+                        cache_namespace: namespace.clone(),
+                        ..config.clone()
+                    });
+                    let entries = cache.entries()?;
+                    println!(
+                        "  {}: {} entries, {} of {}",
+                        namespace.as_deref().unwrap_or("(shared)"),
+                        entries.len(),
+                        format_size(cache_size(&entries)),
+                        format_size(cache.limit()),
+                    );
+                }
+            }
+            println!(
+                "{statistic} (since {} ago)",
+                format_age(since.elapsed().unwrap_or_default().as_secs())
+            );
+        }
+        Command::List => {
+            let entries = cache.entries()?;
+            let mut entries: Vec<(&String, &IndexEntry)> = entries.iter().collect();
+            entries.sort_by_key(|(_, entry)| Reverse(entry.accessed));
+            let now = unix_time(SystemTime::now());
+            let mut stdout = std::io::stdout().lock();
+            for (hash, entry) in entries {
+                writeln!(
+                    stdout,
+                    "{hash} {:>8} {:>5} {}",
+                    format_size(entry_size(entry)),
+                    format_age(now.saturating_sub(entry.accessed)),
+                    entry.source.as_deref().unwrap_or("-"),
+                )?;
+            }
+        }
+        Command::Inspect { hash } => {
+            let entry = cache
+                .open(&hash)?
+                .ok_or_else(|| format!("Cache entry not found: {hash}"))?;
+            let content = inspect_cache(&hash, entry, &cache);
+            if let Some(entry) = cache.entries()?.get(&hash) {
+                println!("Toolchain: {}", entry.toolchain.as_deref().unwrap_or("-"));
+                println!("Source: {}", entry.source.as_deref().unwrap_or("-"));
+            }
+            let content = match (content, read_manifest(&cache, &hash)) {
+                (Err(_), Some(manifest)) => {
+                    println!("Manifest files:");
+                    for path in manifest.files() {
+                        println!("  {}", path.display());
+                    }
+                    return Ok(());
+                }
+                (content, _) => content?,
+            };
+            match content.output.status {
+                Some(status) => println!("Status: {status}"),
+                None => println!("Status: killed"),
+            }
+            println!("Files:");
+            for (index, file) in content.files.iter().enumerate() {
+                match &file.blob {
+                    Some(blob) => println!("  #{index}: {} (blob {blob})", format_size(file.size)),
+                    None => println!("  #{index}: {}", format_size(file.size)),
+                }
+            }
+            println!("Stdout:");
+            std::io::stdout().write_all(&content.output.stdout)?;
+            println!("Stderr:");
+            std::io::stdout().write_all(&content.output.stderr)?;
+        }
+        Command::Verify { remove } => {
+            let mut damaged = 0;
+            for hash in cache.entries()?.keys() {
+                let result = match cache.open(hash) {
+                    Ok(Some(entry)) => inspect_cache(hash, entry, &cache)
+                        .map(drop)
+                        .or_else(|e| read_manifest(&cache, hash).map(drop).ok_or(e)),
+                    Ok(None) => continue,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    println!("{hash}: {e}");
+                    damaged += 1;
+                    if remove {
+                        cache.evict(hash)?;
+                    }
+                }
+            }
+            println!("Damaged entries: {damaged}");
+        }
+        Command::Evict {
+            older_than,
+            max_size,
+        } => {
+            let accessed_after =
+                older_than.map_or(0, |age| unix_time(SystemTime::now()).saturating_sub(age));
+            let removed = cache.shrink(max_size.unwrap_or(u64::MAX), accessed_after)?;
+            println!("Removed entries: {removed}");
+        }
+        Command::Clear => {
+            let removed = cache.shrink(0, 0)?;
+            println!("Removed entries: {removed}");
+        }
+        Command::Explain { source } => {
+            let source = source.absolutize()?;
+            let records = ExplainLog::new(&config.cache).load(&source)?;
+            let (latest, previous) = records.split_last().ok_or_else(|| {
+                format!(
+                    "No cache keys recorded for {} (is explain mode enabled?)",
+                    source.display()
+                )
+            })?;
+            let now = unix_time(SystemTime::now());
+            println!(
+                "Latest key: {} ({} ago)",
+                latest.key,
+                format_age(now.saturating_sub(latest.time))
+            );
+            // Previous key with the least number of changed components.
+            let Some((closest, changes)) = previous
+                .iter()
+                .map(|record| (record, latest.diff(record)))
+                .min_by_key(|(_, changes)| changes.len())
+            else {
+                println!("No previous keys recorded");
+                return Ok(());
+            };
+            println!(
+                "Closest previous key: {} ({} ago)",
+                closest.key,
+                format_age(now.saturating_sub(closest.time))
+            );
+            println!("Changed components:");
+            for change in changes {
+                println!("  {change}");
+            }
+        }
+        Command::Export {
+            bundle,
+            since,
+            toolchain,
+            hash_list,
+        } => {
+            let hashes = match hash_list {
+                Some(path) => Some(
+                    fs::read_to_string(path)?
+                        .lines()
+                        .map(str::trim)
+                        .filter(|line| !line.is_empty())
+                        .map(str::to_string)
+                        .collect::<HashSet<_>>(),
+                ),
+                None => None,
+            };
+            let accessed_after =
+                since.map_or(0, |age| unix_time(SystemTime::now()).saturating_sub(age));
+            let entries = cache.entries()?;
+            let mut entries: Vec<(&String, &IndexEntry)> = entries
+                .iter()
+                .filter(|(hash, entry)| {
+                    entry.accessed >= accessed_after
+                        && toolchain
+                            .as_ref()
+                            .is_none_or(|toolchain| entry.toolchain.as_ref() == Some(toolchain))
+                        && hashes.as_ref().is_none_or(|hashes| hashes.contains(*hash))
+                })
+                .collect();
+            entries.sort_by_key(|(_, entry)| Reverse(entry.accessed));
+            let mut writer = BundleWriter::new(
+                BufWriter::new(File::create(&bundle)?),
+                EntryFormat::new(&config),
+            )?;
+            for (hash, entry) in entries {
+                let meta = EntryMeta {
+                    toolchain: entry.toolchain.clone(),
+                    source: entry.source.clone(),
+                };
+                // Entry may be evicted or damaged, the rest of bundle is still usable.
+                if let Err(e) = writer.add(&cache, hash, &meta) {
+                    println!("{hash}: {e}");
+                }
+            }
+            println!("Exported entries: {}", writer.finish()?);
+        }
+        Command::Import { bundle } => {
+            let summary = import_bundle(BufReader::new(File::open(&bundle)?), &cache)?;
+            println!(
+                "Imported entries: {}, existing: {}, damaged: {}",
+                summary.imported, summary.existing, summary.damaged
+            );
+        }
+    }
+    Ok(())
+}
+
+// Size of all entries, counting every blob once.
+fn cache_size(entries: &IndexEntries) -> u64 {
+    let blobs: HashMap<&str, u64> = entries
+        .values()
+        .flat_map(|entry| entry.blobs.iter())
+        .map(|(blob, size)| (blob.as_str(), *size))
+        .collect();
+    entries.values().map(|entry| entry.size).sum::<u64>() + blobs.values().sum::<u64>()
+}
+
+// Size of entry including referenced blobs, which may be shared with other entries.
+fn entry_size(entry: &IndexEntry) -> u64 {
+    entry.size + entry.blobs.iter().map(|(_, size)| size).sum::<u64>()
+}
+
+// Direct mode manifests are stored along with cache entries.
+fn read_manifest(cache: &FileCache, hash: &str) -> Option<Manifest> {
+    Manifest::read(cache.open(hash).ok()??).ok()
+}
+
+// Parse duration in seconds with optional s/m/h/d suffix.
+fn parse_duration(value: &str) -> Result<u64, String> {
+    parse_number(
+        value,
+        &[('s', 1), ('m', 60), ('h', 60 * 60), ('d', 24 * 60 * 60)],
+    )
+}
+
+// Parse size in bytes with optional K/M/G suffix.
+fn parse_size(value: &str) -> Result<u64, String> {
+    parse_number(
+        value,
+        &[
+            ('K', 1 << 10),
+            ('M', 1 << 20),
+            ('G', 1 << 30),
+            ('T', 1 << 40),
+        ],
+    )
+}
+
+fn parse_number(value: &str, units: &[(char, u64)]) -> Result<u64, String> {
+    let (number, multiplier) = match units.iter().find(|(suffix, _)| {
+        value.ends_with(suffix.to_ascii_lowercase()) || value.ends_with(*suffix)
+    }) {
+        Some((_, multiplier)) => (&value[..value.len() - 1], *multiplier),
+        None => (value, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid value: {value}"))
+}
+
+fn format_size(size: u64) -> String {
+    let mut value = size;
+    for unit in ["B", "K", "M", "G"] {
+        if value < 10 * 1024 {
+            return format!("{value}{unit}");
+        }
+        value /= 1024;
+    }
+    format!("{value}T")
+}
@@ -1,14 +1,27 @@
 use crate::compiler::OutputInfo;
-use crate::config::Config;
-use crate::io::filecache::FileCache;
+use crate::config::{CacheBackendKind, CacheMode, Config};
+use crate::direct::Manifest;
+use crate::inflight::{InFlight, InFlightGuard};
+use crate::io::filecache::{
+    entry_blobs, read_cache, repack_cache, write_cache, EntryFormat, FileCache, StoredBlob,
+};
+use crate::io::hashdb::HashDb;
+use crate::io::httpcache::HttpCache;
 use crate::io::memcache::MemCache;
 use crate::io::statistic::Statistic;
+use crate::trace;
+use crate::trace::Trace;
 use crate::utils::hash_stream;
+use log::warn;
+use std::cmp::max;
 use std::fs;
 use std::fs::File;
-use std::io::{Error, ErrorKind};
+use std::io::{Error, ErrorKind, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
 use std::time::SystemTime;
+use tempfile::NamedTempFile;
 
 #[derive(Clone)]
 struct CacheError {
@@ -16,8 +29,28 @@ struct CacheError {
 }
 
 pub struct Cache {
-    file_cache: FileCache,
+    backend: Arc<dyn CacheBackend>,
+    // Second tier, checked after `backend` miss.
+    remote: Option<Arc<dyn CacheBackend>>,
+    uploader: Option<Arc<Uploader>>,
+    // Background writers of new entries (None - entries are written synchronously).
+    writers: Option<WriterPool>,
+    mode: CacheMode,
+    // Store results of failed compilations.
+    failures: bool,
+    format: EntryFormat,
     file_hash_cache: MemCache<PathBuf, Result<FileHash, CacheError>>,
+    // Persistent file hashes shared with other processes.
+    hash_db: HashDb,
+    in_flight: InFlight,
+}
+
+// Task information stored along with cache entry.
+#[derive(Clone, Debug, Default)]
+pub struct EntryMeta {
+    pub toolchain: Option<String>,
+    // Source file of compilation task.
+    pub source: Option<String>,
 }
 
 #[derive(Clone)]
@@ -27,16 +60,368 @@ pub struct FileHash {
     pub modified: SystemTime,
 }
 
+// Storage for packed cache entries.
+//
+// Entries are opaque streams addressed by cache key hash: packing task outputs
+// into the stream and unpacking them back is done by `Cache`.
+pub trait CacheBackend: Send + Sync {
+    // Open stored entry for reading (None - entry not found).
+    fn get(&self, hash: &str) -> crate::Result<Option<Box<dyn Read>>>;
+    // Start writing of entry. Entry is not visible until commit.
+    // Returns None if the entry is already stored or is being written by someone else.
+    fn put(&self, hash: &str, meta: &EntryMeta)
+        -> crate::Result<Option<Box<dyn CacheEntryWriter>>>;
+    // Start writing of entry, which replaces already stored one on commit.
+    // Returns None if the entry is being written by someone else.
+    fn replace(
+        &self,
+        hash: &str,
+        meta: &EntryMeta,
+    ) -> crate::Result<Option<Box<dyn CacheEntryWriter>>> {
+        self.put(hash, meta)
+    }
+    // Check whether new entries should be stored.
+    fn writable(&self) -> bool {
+        true
+    }
+    // Open decoded content of blob referenced by entries (None - blob not found).
+    fn get_blob(&self, _hash: &str) -> crate::Result<Option<Box<dyn Read>>> {
+        Ok(None)
+    }
+    // Create file with blob content without copying it, for example by reflink.
+    // Returns false if blob content should be copied instead.
+    fn restore_blob(&self, _hash: &str, _path: &Path) -> crate::Result<bool> {
+        Ok(false)
+    }
+    // Check entry existence.
+    fn contains(&self, hash: &str) -> bool;
+    // Remove stored entry.
+    fn evict(&self, hash: &str) -> crate::Result<()>;
+    // Shrink storage according to configured limits.
+    fn cleanup(&self) -> crate::Result<()>;
+    // Shrink storage only if it has grown enough since the last cleanup.
+    // Unlike `cleanup`, it is cheap enough to be called after every build.
+    fn cleanup_if_needed(&self) -> crate::Result<()> {
+        Ok(())
+    }
+}
+
+pub trait CacheEntryWriter: Write + Send {
+    // Store file content outside of entry, so identical files are shared between entries
+    // (None - backend doesn't support blobs).
+    fn put_blob(
+        &mut self,
+        _path: &Path,
+        _format: &EntryFormat,
+    ) -> crate::Result<Option<StoredBlob>> {
+        Ok(None)
+    }
+    // Finish entry writing.
+    fn commit(self: Box<Self>) -> crate::Result<()>;
+}
+
+pub fn create_backend(config: &Config) -> std::io::Result<Arc<dyn CacheBackend>> {
+    Ok(match config.cache_backend {
+        CacheBackendKind::File => Arc::new(FileCache::new(config)),
+        CacheBackendKind::Http => Arc::new(HttpCache::new(config)?),
+    })
+}
+
+// Copies locally stored entries into remote cache on background thread.
+struct Uploader {
+    sender: Mutex<Option<crossbeam_channel::Sender<(String, EntryMeta)>>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Uploader {
+    fn new(
+        local: Arc<dyn CacheBackend>,
+        remote: Arc<dyn CacheBackend>,
+        format: EntryFormat,
+    ) -> Self {
+        let (sender, receiver) = crossbeam_channel::unbounded::<(String, EntryMeta)>();
+        let thread = std::thread::spawn(move || {
+            for (hash, meta) in receiver {
+                if let Err(e) = copy_entry(local.as_ref(), remote.as_ref(), &hash, &meta, &format) {
+                    warn!("Can't upload cache entry {}: {}", hash, e);
+                }
+            }
+        });
+        Uploader {
+            sender: Mutex::new(Some(sender)),
+            thread: Mutex::new(Some(thread)),
+        }
+    }
+
+    fn upload(&self, hash: &str, meta: &EntryMeta) {
+        if let Some(sender) = self.sender.lock().unwrap().as_ref() {
+            drop(sender.send((hash.to_string(), meta.clone())));
+        }
+    }
+
+    fn flush(&self) {
+        drop(self.sender.lock().unwrap().take());
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            drop(thread.join());
+        }
+    }
+}
+
+// Writes cache entries on background threads, so tasks depending on compiled outputs don't wait
+// for compression. Count of queued entries is limited: compilation threads block when it is exceeded.
+struct WriterPool {
+    sender: Mutex<Option<crossbeam_channel::Sender<WriteJob>>>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+    format: EntryFormat,
+    uploader: Option<Arc<Uploader>>,
+    // Count of queued and running writes.
+    pending: Arc<(Mutex<usize>, Condvar)>,
+    // Statistic of completed writes not yet added to build statistic.
+    statistic: Arc<Mutex<Statistic>>,
+}
+
+// Compilation result waiting to be packed into cache entry.
+struct WriteJob {
+    hash: String,
+    meta: EntryMeta,
+    writer: Box<dyn CacheEntryWriter>,
+    outputs: Vec<PathBuf>,
+    output: OutputInfo,
+    // Copies of output files, removed after write.
+    snapshots: Vec<NamedTempFile<()>>,
+    // Identical compilations wait until entry is written.
+    _in_flight: Option<InFlightGuard>,
+    // Trace of compilation, background writes are recorded to lanes of writer threads.
+    trace: Option<Arc<Trace>>,
+}
+
+impl WriterPool {
+    // Writer threads are traced to lanes starting from `first_lane`, after lanes of build workers.
+    fn new(
+        threads: usize,
+        first_lane: usize,
+        format: EntryFormat,
+        uploader: Option<Arc<Uploader>>,
+    ) -> Self {
+        let (sender, receiver) = crossbeam_channel::bounded::<WriteJob>(threads);
+        let pending = Arc::new((Mutex::new(0_usize), Condvar::new()));
+        let statistic = Arc::new(Mutex::new(Statistic::new()));
+        let threads = (0..threads)
+            .map(|index| {
+                let receiver = receiver.clone();
+                let format = format.clone();
+                let uploader = uploader.clone();
+                let pending = pending.clone();
+                let statistic = statistic.clone();
+                std::thread::spawn(move || {
+                    for mut job in receiver {
+                        let _lane = job.trace.take().map(|trace| {
+                            trace.enter(first_lane + index, || format!("cache writer #{index}"))
+                        });
+                        let written = Statistic::new();
+                        job.run(&written, &format, uploader.as_deref());
+                        statistic.lock().unwrap().add(&written);
+                        let (count, condvar) = &*pending;
+                        *count.lock().unwrap() -= 1;
+                        condvar.notify_all();
+                    }
+                })
+            })
+            .collect();
+        WriterPool {
+            sender: Mutex::new(Some(sender)),
+            threads: Mutex::new(threads),
+            format,
+            uploader,
+            pending,
+            statistic,
+        }
+    }
+
+    fn submit(&self, statistic: &Statistic, mut job: WriteJob) {
+        if let Err(e) = job.snapshot() {
+            write_failed(statistic, &job.hash, &e);
+            if job.output.success() {
+                statistic.add_miss(0);
+            }
+            return;
+        }
+        let Some(sender) = self.sender.lock().unwrap().clone() else {
+            // Writers are already stopped.
+            job.run(statistic, &self.format, self.uploader.as_deref());
+            return;
+        };
+        *self.pending.0.lock().unwrap() += 1;
+        if let Err(e) = sender.send(job) {
+            *self.pending.0.lock().unwrap() -= 1;
+            e.into_inner()
+                .run(statistic, &self.format, self.uploader.as_deref());
+        }
+    }
+
+    fn wait(&self, statistic: &Statistic) {
+        let (count, condvar) = &*self.pending;
+        drop(condvar.wait_while(count.lock().unwrap(), |count| *count > 0));
+        statistic.add(&std::mem::take(&mut *self.statistic.lock().unwrap()));
+    }
+
+    fn shutdown(&self) {
+        drop(self.sender.lock().unwrap().take());
+        for thread in self.threads.lock().unwrap().drain(..) {
+            drop(thread.join());
+        }
+    }
+}
+
+impl WriteJob {
+    // Replace outputs by their snapshots, so the task outputs may be changed before entry is written.
+    fn snapshot(&mut self) -> crate::Result<()> {
+        // Only status and compiler output are stored for failed compilation.
+        if !self.output.success() {
+            self.outputs.clear();
+        }
+        for path in &mut self.outputs {
+            let snapshot = snapshot_file(path)?;
+            *path = snapshot.path().to_path_buf();
+            self.snapshots.push(snapshot);
+        }
+        Ok(())
+    }
+
+    fn run(self, statistic: &Statistic, format: &EntryFormat, uploader: Option<&Uploader>) {
+        let _span = trace::span("cache write");
+        trace::annotate("hash", &self.hash);
+        match write_cache(statistic, self.writer, self.outputs, &self.output, format) {
+            Ok(()) => {
+                if let Some(uploader) = uploader {
+                    uploader.upload(&self.hash, &self.meta);
+                }
+            }
+            Err(e) => {
+                write_failed(statistic, &self.hash, &e);
+                if self.output.success() {
+                    statistic.add_miss(0);
+                }
+            }
+        }
+    }
+}
+
+fn is_damaged(error: &crate::Error) -> bool {
+    match error {
+        crate::Error::Cache(e) => !matches!(e, crate::io::filecache::CacheError::MutexError(_)),
+        _ => false,
+    }
+}
+
+// Reflink file to temporary name in the same directory, copy it if reflinks are not supported.
+// Hardlink would share content with the output file, which may be modified in place.
+fn snapshot_file(path: &Path) -> crate::Result<NamedTempFile<()>> {
+    let parent = path
+        .parent()
+        .ok_or_else(|| format!("Invalid output path: {}", path.display()))?;
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    Ok(tempfile::Builder::new()
+        .prefix(&format!("~tmp~{name}"))
+        .make_in(parent, |temp| {
+            reflink::reflink_or_copy(path, temp).map(drop)
+        })?)
+}
+
+fn write_failed(statistic: &Statistic, hash: &str, error: &crate::Error) {
+    warn!("Can't write cache entry {}: {}", hash, error);
+    statistic.inc_write_error();
+}
+
+fn copy_entry(
+    from: &dyn CacheBackend,
+    to: &dyn CacheBackend,
+    hash: &str,
+    meta: &EntryMeta,
+    format: &EntryFormat,
+) -> crate::Result<()> {
+    let Some(entry) = from.get(hash)? else {
+        return Ok(());
+    };
+    let Some(mut writer) = to.put(hash, meta)? else {
+        return Ok(());
+    };
+    let has_blobs = !entry_blobs(hash, entry)?.is_empty();
+    if let Some(mut entry) = from.get(hash)? {
+        if has_blobs {
+            // Blobs are not shared between backends, so their content is packed into the copy.
+            repack_cache(hash, entry, from, writer, format)?;
+        } else {
+            std::io::copy(&mut entry, &mut writer)?;
+            writer.commit()?;
+        }
+    }
+    Ok(())
+}
+
+// Passes all read data through to writer.
+struct TeeReader<R: Read, W: Write> {
+    reader: R,
+    writer: W,
+}
+
+impl<R: Read, W: Write> Read for TeeReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.reader.read(buf)?;
+        self.writer.write_all(&buf[..size])?;
+        Ok(size)
+    }
+}
+
 pub trait FileHasher {
     fn file_hash(&self, path: &Path) -> Result<FileHash, Error>;
 }
 
 impl Cache {
-    #[must_use]
-    pub fn new(config: &Config) -> Self {
+    pub fn new(config: &Config) -> std::io::Result<Self> {
+        let backend = create_backend(config)?;
+        let remote: Option<Arc<dyn CacheBackend>> =
+            match (config.cache_backend, &config.cache_remote_url) {
+                (CacheBackendKind::File, Some(_)) => Some(Arc::new(HttpCache::new(config)?)),
+                _ => None,
+            };
+        Ok(Cache::with_backends(config, backend, remote))
+    }
+
+    fn with_backends(
+        config: &Config,
+        backend: Arc<dyn CacheBackend>,
+        remote: Option<Arc<dyn CacheBackend>>,
+    ) -> Self {
+        let uploader = remote
+            .as_ref()
+            .filter(|remote| config.cache_mode.writes() && remote.writable())
+            .map(|remote| {
+                Arc::new(Uploader::new(
+                    backend.clone(),
+                    remote.clone(),
+                    EntryFormat::new(config),
+                ))
+            });
+        let writers = (config.cache_writers > 0 && config.cache_mode.writes()).then(|| {
+            WriterPool::new(
+                config.cache_writers,
+                max(config.process_limit, 1),
+                EntryFormat::new(config),
+                uploader.clone(),
+            )
+        });
         Cache {
-            file_cache: FileCache::new(config),
+            backend,
+            remote,
+            uploader,
+            writers,
+            mode: config.cache_mode,
+            failures: config.cache_failures,
+            format: EntryFormat::new(config),
             file_hash_cache: MemCache::default(),
+            hash_db: HashDb::new(&config.cache),
+            in_flight: InFlight::new(&config.cache),
         }
     }
 
@@ -44,49 +429,780 @@ impl Cache {
         &self,
         statistic: &Statistic,
         hash: &str,
+        meta: &EntryMeta,
         outputs: Vec<PathBuf>,
         worker: F,
     ) -> crate::Result<OutputInfo> {
-        self.file_cache.run_cached(statistic, hash, outputs, worker)
+        if let Some(output) = self.read_cached(statistic, hash, meta, &outputs) {
+            trace::annotate("cache", "hit");
+            return Ok(output);
+        }
+        // Identical compilation may be already running, its result is taken from cache then.
+        let in_flight = if self.mode == CacheMode::Normal && self.backend.writable() {
+            let (guard, waited) = self.in_flight.acquire(hash);
+            if waited {
+                if let Some(output) = self.read_cached(statistic, hash, meta, &outputs) {
+                    statistic.inc_in_flight_hit();
+                    trace::annotate("cache", "in-flight hit");
+                    return Ok(output);
+                }
+            }
+            Some(guard)
+        } else {
+            None
+        };
+        trace::annotate("cache", "miss");
+        let toolchain = meta.toolchain.as_deref().unwrap_or("unknown");
+        // Run task and save result to cache.
+        let output = match worker() {
+            Ok(output) => output,
+            Err(e) => {
+                statistic.inc_error();
+                statistic.update_toolchain(toolchain, |s| s.error_count += 1);
+                return Err(e);
+            }
+        };
+        if output.success() {
+            statistic.update_toolchain(toolchain, |s| s.miss_count += 1);
+        } else {
+            statistic.inc_error();
+            statistic.update_toolchain(toolchain, |s| s.error_count += 1);
+        }
+        // Existing entry is replaced even if new result is not stored.
+        if self.mode == CacheMode::Recache && self.writable() {
+            if let Err(e) = self.backend.evict(hash) {
+                warn!("Can't remove cache entry {}: {}", hash, e);
+            }
+        }
+        // Process killed by signal may succeed on the next attempt.
+        let cacheable = output.success() || (self.failures && output.status.is_some());
+        let writer = if cacheable && self.writable() {
+            self.backend.put(hash, meta).unwrap_or_else(|e| {
+                write_failed(statistic, hash, &e);
+                None
+            })
+        } else {
+            None
+        };
+        let Some(writer) = writer else {
+            // Failed compilation is already counted as error.
+            if output.success() {
+                statistic.add_miss(0);
+            }
+            return Ok(output);
+        };
+        let job = WriteJob {
+            hash: hash.to_string(),
+            meta: meta.clone(),
+            writer,
+            outputs,
+            output: output.clone(),
+            snapshots: Vec::new(),
+            _in_flight: in_flight,
+            trace: trace::current(),
+        };
+        match &self.writers {
+            Some(writers) => writers.submit(statistic, job),
+            None => job.run(statistic, &self.format, self.uploader.as_deref()),
+        }
+        Ok(output)
+    }
+
+    // Restore task outputs from cache (None - entry not found).
+    pub fn read_cached(
+        &self,
+        statistic: &Statistic,
+        hash: &str,
+        meta: &EntryMeta,
+        outputs: &[PathBuf],
+    ) -> Option<OutputInfo> {
+        if !self.mode.reads() {
+            return None;
+        }
+        let _span = trace::span("cache lookup");
+        let toolchain = meta.toolchain.as_deref().unwrap_or("unknown");
+        // Try to read data from cache.
+        if let Ok(Some(entry)) = self.backend.get(hash) {
+            match read_cache(statistic, hash, entry, outputs, self.backend.as_ref()) {
+                Ok(output) => {
+                    statistic.update_toolchain(toolchain, |s| s.hit_count += 1);
+                    return Some(output);
+                }
+                Err(e) => self.read_failed(statistic, self.backend.as_ref(), hash, &e),
+            }
+        }
+        // Try to read data from remote cache.
+        let remote = self.remote.as_ref()?;
+        let output = self.read_remote(statistic, remote.as_ref(), hash, meta, outputs)?;
+        statistic.inc_remote_hit();
+        statistic.update_toolchain(toolchain, |s| s.hit_count += 1);
+        Some(output)
+    }
+
+    // Load include manifest of direct mode.
+    pub fn load_manifest(&self, key: &str) -> Option<Manifest> {
+        if !self.mode.reads() {
+            return None;
+        }
+        let entry = self.backend.get(key).ok()??;
+        match Manifest::read(entry) {
+            Ok(manifest) => Some(manifest),
+            Err(e) => {
+                warn!("Can't read manifest {}: {}", key, e);
+                None
+            }
+        }
+    }
+
+    // Replace include manifest of direct mode.
+    pub fn store_manifest(
+        &self,
+        key: &str,
+        meta: &EntryMeta,
+        manifest: &Manifest,
+    ) -> crate::Result<()> {
+        if !self.writable() {
+            return Ok(());
+        }
+        // Readers see either previous or new manifest, never a missing one.
+        if let Some(mut writer) = self.backend.replace(key, meta)? {
+            manifest.write(&mut writer)?;
+            writer.commit()?;
+        }
+        Ok(())
+    }
+
+    // Unpack remote entry and store its copy in local cache.
+    fn read_remote(
+        &self,
+        statistic: &Statistic,
+        remote: &dyn CacheBackend,
+        hash: &str,
+        meta: &EntryMeta,
+        outputs: &[PathBuf],
+    ) -> Option<OutputInfo> {
+        let entry = match remote.get(hash) {
+            Ok(entry) => entry?,
+            Err(e) => {
+                warn!("Can't download cache entry {}: {}", hash, e);
+                return None;
+            }
+        };
+        let writer = match self.writable().then(|| self.backend.put(hash, meta)) {
+            Some(Ok(Some(writer))) => writer,
+            _ => {
+                return read_cache(statistic, hash, entry, outputs, remote)
+                    .map_err(|e| self.read_failed(statistic, remote, hash, &e))
+                    .ok()
+            }
+        };
+        // Local copy is not committed if remote entry is damaged.
+        let mut tee = TeeReader {
+            reader: entry,
+            writer,
+        };
+        let output = read_cache(statistic, hash, &mut tee, outputs, remote)
+            .map_err(|e| self.read_failed(statistic, remote, hash, &e))
+            .ok()?;
+        if let Err(e) = std::io::copy(&mut tee, &mut std::io::sink())
+            .map_err(crate::Error::from)
+            .and_then(|_| tee.writer.commit())
+        {
+            warn!("Can't store remote cache entry {}: {}", hash, e);
+        }
+        Some(output)
+    }
+
+    // Remove entry that can't be unpacked, so it gets replaced by a fresh task result.
+    // Other errors (like locked output file or network failure) say nothing about the entry,
+    // so it is kept and the read is treated as a miss.
+    fn read_failed(
+        &self,
+        statistic: &Statistic,
+        backend: &dyn CacheBackend,
+        hash: &str,
+        error: &crate::Error,
+    ) {
+        if !is_damaged(error) {
+            warn!("Can't read cache entry {}: {}", hash, error);
+            return;
+        }
+        warn!("Can't read cache entry {}, discarding it: {}", hash, error);
+        statistic.inc_corrupt();
+        if self.mode.writes() && backend.writable() {
+            if let Err(e) = backend.evict(hash) {
+                warn!("Can't remove cache entry {}: {}", hash, e);
+            }
+        }
+    }
+
+    fn writable(&self) -> bool {
+        self.mode.writes() && self.backend.writable()
+    }
+
+    #[must_use]
+    pub fn mode(&self) -> CacheMode {
+        self.mode
     }
 
     pub fn cleanup(&self) -> crate::Result<()> {
-        self.file_cache.cleanup()
+        if !self.mode.writes() {
+            return Ok(());
+        }
+        self.backend.cleanup()
+    }
+
+    // Wait for background writes of cache entries and add their results to statistic.
+    pub fn flush_writes(&self, statistic: &Statistic) {
+        if let Some(writers) = &self.writers {
+            writers.wait(statistic);
+        }
+    }
+
+    // Wait for completion of background cache operations.
+    pub fn flush(&self) {
+        // Written entries are uploaded, so writers are stopped first.
+        if let Some(writers) = &self.writers {
+            writers.shutdown();
+        }
+        if let Some(uploader) = &self.uploader {
+            uploader.flush();
+        }
+        if !self.mode.writes() {
+            return;
+        }
+        // Every frontend flushes cache on exit, so files of finished compilations don't pile up.
+        if let Err(e) = self.hash_db.cleanup() {
+            warn!("Can't cleanup file hashes: {}", e);
+        }
+        if let Err(e) = self.in_flight.cleanup() {
+            warn!("Can't cleanup in-flight compilation locks: {}", e);
+        }
+        if let Err(e) = self.backend.cleanup_if_needed() {
+            warn!("Can't cleanup cache: {}", e);
+        }
+    }
+}
+
+impl Drop for Cache {
+    fn drop(&mut self) {
+        self.flush();
     }
 }
 
 fn file_hash_helper(
     path: &Path,
     cached: Option<Result<FileHash, CacheError>>,
+    hash_db: Option<&HashDb>,
+    store: bool,
 ) -> Result<FileHash, Error> {
+    let started = SystemTime::now();
     let stat = fs::metadata(path)?;
     let modified = stat.modified()?;
-    // Validate cached value.
-    if let Some(Ok(value)) = cached {
-        if value.size == stat.len() && value.modified == modified {
-            return Ok(value);
-        }
+    let valid = |value: &FileHash| value.size == stat.len() && value.modified == modified;
+    // Validate cached value, then value persisted by previous processes.
+    if let Some(value) = cached.and_then(Result::ok).filter(valid) {
+        return Ok(value);
+    }
+    if let Some(value) = hash_db.and_then(|db| db.get(path)).filter(valid) {
+        return Ok(value);
     }
     let mut file = File::open(path)?;
     let hash = hash_stream(&mut file)?;
-    Ok(FileHash {
+    let value = FileHash {
         hash,
         size: stat.len(),
         modified,
-    })
+    };
+    if let Some(hash_db) = hash_db.filter(|_| store) {
+        if let Err(e) = hash_db.put(path, &value, started) {
+            warn!("Can't store hash of {}: {}", path.display(), e);
+        }
+    }
+    Ok(value)
 }
 
 impl FileHasher for Cache {
     fn file_hash(&self, path: &Path) -> Result<FileHash, Error> {
+        // Disabled cache doesn't touch cache directory, read-only cache doesn't modify it.
+        let hash_db = (self.mode != CacheMode::Disabled).then_some(&self.hash_db);
         self.file_hash_cache
             .run_cached(
                 path.to_path_buf(),
                 |cached: Option<Result<FileHash, CacheError>>| -> Result<FileHash, CacheError> {
-                    file_hash_helper(path, cached).map_err(|e| CacheError {
-                        error_msg: e.to_string(),
+                    file_hash_helper(path, cached, hash_db, self.mode.writes()).map_err(|e| {
+                        CacheError {
+                            error_msg: e.to_string(),
+                        }
                     })
                 },
             )
             .map_err(|e| Error::new(ErrorKind::Other, e.error_msg))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use crate::cache::{Cache, CacheBackend, EntryMeta};
+    use crate::compiler::OutputInfo;
+    use crate::config::{CacheCompression, CacheMode, Config};
+    use crate::direct::Manifest;
+    use crate::io::filecache::FileCache;
+    use crate::io::statistic::Statistic;
+
+    fn write_output(path: &Path) -> crate::Result<OutputInfo> {
+        fs::write(path, b"object")?;
+        Ok(OutputInfo {
+            status: Some(0),
+            stdout: b"out".to_vec(),
+            stderr: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn test_run_file_cached() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: temp.path().join("cache"),
+            ..Config::default()
+        };
+        let cache = Cache::new(&config).unwrap();
+        let statistic = Statistic::new();
+        let output = temp.path().join("output.o");
+        let hash = "0123456789abcdef";
+
+        let result = cache
+            .run_file_cached(
+                &statistic,
+                hash,
+                &EntryMeta::default(),
+                vec![output.clone()],
+                || write_output(&output),
+            )
+            .unwrap();
+        assert_eq!(result.stdout, b"out");
+        cache.flush_writes(&statistic);
+        fs::remove_file(&output).unwrap();
+
+        let result = cache
+            .run_file_cached(
+                &statistic,
+                hash,
+                &EntryMeta::default(),
+                vec![output.clone()],
+                || {
+                    unreachable!();
+                },
+            )
+            .unwrap();
+        assert_eq!(result.stdout, b"out");
+        assert_eq!(fs::read(&output).unwrap(), b"object");
+        assert_eq!(statistic.hit_count.load(Ordering::Relaxed), 1);
+        assert_eq!(statistic.miss_count.load(Ordering::Relaxed), 1);
+
+        // Lock files of finished compilations are removed on exit.
+        cache.flush();
+        let in_flight = config.cache.join("inflight");
+        assert_eq!(fs::read_dir(in_flight).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_run_file_cached_background() {
+        let temp = tempfile::tempdir().unwrap();
+        let cache = Cache::new(&Config {
+            cache: temp.path().join("cache"),
+            cache_writers: 1,
+            ..Config::default()
+        })
+        .unwrap();
+        let statistic = Statistic::new();
+        let hash = "0123456789abcdef";
+        let output = temp.path().join("output.o");
+        cache
+            .run_file_cached(
+                &statistic,
+                hash,
+                &EntryMeta::default(),
+                vec![output.clone()],
+                || write_output(&output),
+            )
+            .unwrap();
+        // Entry is written from snapshot of output.
+        fs::remove_file(&output).unwrap();
+        cache.flush_writes(&statistic);
+        assert_eq!(statistic.miss_count.load(Ordering::Relaxed), 1);
+        assert_eq!(statistic.write_error_count.load(Ordering::Relaxed), 0);
+        assert_eq!(fs::read_dir(temp.path()).unwrap().count(), 1);
+
+        cache
+            .run_file_cached(
+                &statistic,
+                hash,
+                &EntryMeta::default(),
+                vec![output.clone()],
+                || unreachable!(),
+            )
+            .unwrap();
+        assert_eq!(fs::read(&output).unwrap(), b"object");
+    }
+
+    #[test]
+    fn test_run_file_cached_in_flight() {
+        let temp = tempfile::tempdir().unwrap();
+        let cache = Cache::new(&Config {
+            cache: temp.path().join("cache"),
+            ..Config::default()
+        })
+        .unwrap();
+        let statistic = Statistic::new();
+        let compiled = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            for index in 0..2 {
+                let output = temp.path().join(format!("output{index}.o"));
+                let (cache, statistic, compiled) = (&cache, &statistic, &compiled);
+                scope.spawn(move || {
+                    cache
+                        .run_file_cached(
+                            statistic,
+                            "0123456789abcdef",
+                            &EntryMeta::default(),
+                            vec![output.clone()],
+                            || {
+                                compiled.fetch_add(1, Ordering::Relaxed);
+                                std::thread::sleep(Duration::from_millis(100));
+                                write_output(&output)
+                            },
+                        )
+                        .unwrap();
+                    assert_eq!(fs::read(&output).unwrap(), b"object");
+                });
+            }
+        });
+        cache.flush_writes(&statistic);
+        // Second task waits for the first one and takes its result.
+        assert_eq!(compiled.load(Ordering::Relaxed), 1);
+        assert_eq!(statistic.hit_count.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_run_file_cached_two_tier() {
+        let temp = tempfile::tempdir().unwrap();
+        let local_config = Config {
+            cache: temp.path().join("local"),
+            ..Config::default()
+        };
+        let remote_config = Config {
+            cache: temp.path().join("remote"),
+            ..Config::default()
+        };
+        let local: Arc<dyn CacheBackend> = Arc::new(FileCache::new(&local_config));
+        let remote: Arc<dyn CacheBackend> = Arc::new(FileCache::new(&remote_config));
+        let output = temp.path().join("output.o");
+        let hash = "0123456789abcdef";
+
+        // Miss is uploaded to remote tier.
+        let cache = Cache::with_backends(&local_config, local.clone(), Some(remote.clone()));
+        cache
+            .run_file_cached(
+                &Statistic::new(),
+                hash,
+                &EntryMeta::default(),
+                vec![output.clone()],
+                || write_output(&output),
+            )
+            .unwrap();
+        cache.flush();
+        assert!(remote.contains(hash));
+
+        // Remote hit is copied into local tier.
+        local.evict(hash).unwrap();
+        let statistic = Statistic::new();
+        let cache = Cache::with_backends(&local_config, local.clone(), Some(remote));
+        cache
+            .run_file_cached(
+                &statistic,
+                hash,
+                &EntryMeta::default(),
+                vec![output.clone()],
+                || {
+                    unreachable!();
+                },
+            )
+            .unwrap();
+        assert_eq!(statistic.remote_hit_count.load(Ordering::Relaxed), 1);
+        assert!(local.contains(hash));
+    }
+
+    #[test]
+    fn test_run_file_cached_corrupt() {
+        // Damage packed file content.
+        check_corrupt(CacheCompression::None, |data| {
+            let pos = data.windows(6).position(|w| w == b"object").unwrap();
+            data[pos] = b'O';
+        });
+        // Compressed payload is damaged at the end, where checksum of lz4 frame is stored.
+        check_corrupt(CacheCompression::Lz4, |data| {
+            *data.last_mut().unwrap() ^= 0xFF
+        });
+        check_corrupt(CacheCompression::Zstd, |data| {
+            let pos = data.len() / 2;
+            data[pos] ^= 0xFF;
+        });
+        // Truncated entry.
+        check_corrupt(CacheCompression::Lz4, |data| data.truncate(data.len() - 10));
+    }
+
+    fn check_corrupt(cache_compression: CacheCompression, damage: impl Fn(&mut Vec<u8>)) {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: temp.path().join("cache"),
+            cache_compression,
+            ..Config::default()
+        };
+        let cache = Cache::new(&config).unwrap();
+        let statistic = Statistic::new();
+        let output = temp.path().join("output.o");
+        let hash = "0123456789abcdef";
+        cache
+            .run_file_cached(
+                &statistic,
+                hash,
+                &EntryMeta::default(),
+                vec![output.clone()],
+                || write_output(&output),
+            )
+            .unwrap();
+        cache.flush_writes(&statistic);
+
+        let entry = config.cache.join("01").join("23456789abcdef.lz4");
+        let mut data = fs::read(&entry).unwrap();
+        damage(&mut data);
+        fs::write(&entry, &data).unwrap();
+
+        let mut compiled = false;
+        cache
+            .run_file_cached(
+                &statistic,
+                hash,
+                &EntryMeta::default(),
+                vec![output.clone()],
+                || {
+                    compiled = true;
+                    write_output(&output)
+                },
+            )
+            .unwrap();
+        cache.flush_writes(&statistic);
+        assert!(compiled);
+        assert_eq!(statistic.corrupt_count.load(Ordering::Relaxed), 1);
+        assert_eq!(fs::read(&output).unwrap(), b"object");
+        // Entry is replaced by fresh result.
+        assert_eq!(statistic.miss_count.load(Ordering::Relaxed), 2);
+        assert_ne!(fs::read(&entry).unwrap(), data);
+    }
+
+    #[test]
+    fn test_run_file_cached_output_error() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: temp.path().join("cache"),
+            ..Config::default()
+        };
+        let cache = Cache::new(&config).unwrap();
+        let statistic = Statistic::new();
+        let output = temp.path().join("output.o");
+        let hash = "0123456789abcdef";
+        cache
+            .run_file_cached(
+                &statistic,
+                hash,
+                &EntryMeta::default(),
+                vec![output.clone()],
+                || write_output(&output),
+            )
+            .unwrap();
+        cache.flush_writes(&statistic);
+
+        // Output can't be replaced, but entry is fine.
+        fs::remove_file(&output).unwrap();
+        fs::create_dir_all(output.join("locked")).unwrap();
+        assert!(cache
+            .read_cached(
+                &statistic,
+                hash,
+                &EntryMeta::default(),
+                std::slice::from_ref(&output)
+            )
+            .is_none());
+        assert_eq!(statistic.corrupt_count.load(Ordering::Relaxed), 0);
+
+        fs::remove_dir_all(&output).unwrap();
+        assert!(cache
+            .read_cached(
+                &statistic,
+                hash,
+                &EntryMeta::default(),
+                std::slice::from_ref(&output)
+            )
+            .is_some());
+        assert_eq!(fs::read(&output).unwrap(), b"object");
+    }
+
+    #[test]
+    fn test_run_file_cached_failures() {
+        let temp = tempfile::tempdir().unwrap();
+        let cache = Cache::new(&Config {
+            cache: temp.path().join("cache"),
+            cache_failures: true,
+            ..Config::default()
+        })
+        .unwrap();
+        let statistic = Statistic::new();
+        let output = temp.path().join("output.o");
+        let hash = "0123456789abcdef";
+        let fail = || {
+            Ok(OutputInfo {
+                status: Some(2),
+                stdout: Vec::new(),
+                stderr: b"error".to_vec(),
+            })
+        };
+
+        cache
+            .run_file_cached(
+                &statistic,
+                hash,
+                &EntryMeta::default(),
+                vec![output.clone()],
+                fail,
+            )
+            .unwrap();
+        cache.flush_writes(&statistic);
+        assert_eq!(statistic.error_count.load(Ordering::Relaxed), 1);
+        assert_eq!(statistic.miss_count.load(Ordering::Relaxed), 0);
+        // Stale output is removed on replay.
+        fs::write(&output, b"stale").unwrap();
+        let result = cache
+            .run_file_cached(
+                &statistic,
+                hash,
+                &EntryMeta::default(),
+                vec![output.clone()],
+                || unreachable!(),
+            )
+            .unwrap();
+        assert_eq!(result.status, Some(2));
+        assert_eq!(result.stderr, b"error");
+        assert!(!output.exists());
+        assert_eq!(statistic.hit_count.load(Ordering::Relaxed), 1);
+
+        // Failure is not stored without cache_failures.
+        let cache = Cache::new(&Config {
+            cache: temp.path().join("uncached"),
+            ..Config::default()
+        })
+        .unwrap();
+        let statistic = Statistic::new();
+        cache
+            .run_file_cached(
+                &statistic,
+                hash,
+                &EntryMeta::default(),
+                vec![output.clone()],
+                fail,
+            )
+            .unwrap();
+        cache.flush_writes(&statistic);
+        assert_eq!(statistic.error_count.load(Ordering::Relaxed), 1);
+        assert_eq!(statistic.miss_count.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_run_file_cached_modes() {
+        let temp = tempfile::tempdir().unwrap();
+        let output = temp.path().join("output.o");
+        let hash = "0123456789abcdef";
+        let config = |cache_mode| Config {
+            cache: temp.path().join("cache"),
+            cache_mode,
+            ..Config::default()
+        };
+        let run = |cache: &Cache, content: &'static [u8]| {
+            let mut compiled = false;
+            let statistic = Statistic::new();
+            cache
+                .run_file_cached(
+                    &statistic,
+                    hash,
+                    &EntryMeta::default(),
+                    vec![output.clone()],
+                    || {
+                        compiled = true;
+                        fs::write(&output, content)?;
+                        Ok(OutputInfo {
+                            status: Some(0),
+                            stdout: Vec::new(),
+                            stderr: Vec::new(),
+                        })
+                    },
+                )
+                .unwrap();
+            cache.flush_writes(&statistic);
+            compiled
+        };
+
+        // Read-only and disabled cache don't store entries.
+        let readonly = Cache::new(&config(CacheMode::ReadOnly)).unwrap();
+        assert!(run(&readonly, b"one"));
+        assert!(run(&readonly, b"one"));
+        let disabled = Cache::new(&config(CacheMode::Disabled)).unwrap();
+        assert!(run(&disabled, b"one"));
+
+        let normal = Cache::new(&config(CacheMode::Normal)).unwrap();
+        assert!(run(&normal, b"one"));
+        // Read-only hit doesn't modify cache directory.
+        let journal = temp.path().join("cache").join("index.journal");
+        let journal_size = fs::metadata(&journal).unwrap().len();
+        assert!(!run(&readonly, b"two"));
+        assert_eq!(fs::read(&output).unwrap(), b"one");
+        assert_eq!(fs::metadata(&journal).unwrap().len(), journal_size);
+        assert!(run(&disabled, b"two"));
+
+        // Recache always compiles and replaces entry.
+        let recache = Cache::new(&config(CacheMode::Recache)).unwrap();
+        assert!(run(&recache, b"two"));
+        assert!(!run(&normal, b"one"));
+        assert_eq!(fs::read(&output).unwrap(), b"two");
+    }
+
+    #[test]
+    fn test_store_manifest() {
+        let temp = tempfile::tempdir().unwrap();
+        let cache = Cache::new(&Config {
+            cache: temp.path().join("cache"),
+            ..Config::default()
+        })
+        .unwrap();
+        let header = temp.path().join("a.h");
+        fs::write(&header, "1").unwrap();
+        let started = SystemTime::now() + Duration::from_secs(10);
+        let key = "fedcba9876543210";
+
+        for result in ["one", "two"] {
+            let mut manifest = Manifest::default();
+            assert!(manifest.add(&cache, vec![header.clone()], result, started));
+            cache
+                .store_manifest(key, &EntryMeta::default(), &manifest)
+                .unwrap();
+        }
+        // Stored manifest is replaced by the new one.
+        let manifest = cache.load_manifest(key).unwrap();
+        assert_eq!(manifest.find(&cache), Some("two"));
+    }
+}
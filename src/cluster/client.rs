@@ -102,11 +102,8 @@ impl<'a, R: 'a + Read> Read for ReadWrapper<'a, R> {
 }
 
 impl RemoteToolchain {
-    fn compile_remote(
-        &self,
-        state: &SharedState,
-        task: &CompileStep,
-    ) -> Result<CompileResponse, Error> {
+    // Find builder for remote compilation.
+    fn remote_builder(&self, task: &CompileStep) -> Result<(String, SocketAddr), Error> {
         let name = self
             .identifier()
             .ok_or_else(|| Error::new(ErrorKind::Other, "Can't get toolchain name"))?;
@@ -120,8 +117,19 @@ impl RemoteToolchain {
                 "Remote precompiled header generation is not supported",
             ));
         }
+        Ok((name, addr))
+    }
 
-        let base_url = get_base_url(&addr);
+    fn compile_remote(
+        &self,
+        state: &SharedState,
+        task: &CompileStep,
+        name: String,
+        addr: &SocketAddr,
+    ) -> Result<CompileResponse, Error> {
+        let _span = crate::trace::span("remote compile");
+        crate::trace::annotate("builder", addr);
+        let base_url = get_base_url(addr);
 
         let preprocessed = if let Preprocessed(preprocessed) = &task.input {
             preprocessed
@@ -291,13 +299,22 @@ impl Toolchain for RemoteToolchain {
     }
 
     fn run_compile(&self, state: &SharedState, task: CompileStep) -> crate::Result<OutputInfo> {
-        match self.compile_remote(state, &task) {
+        let (name, addr) = match self.remote_builder(&task) {
+            Ok(builder) => builder,
+            Err(e) => {
+                trace!("Use local build: {}", e);
+                return self.local.run_compile(state, task);
+            }
+        };
+        state.statistic.inc_remote_attempt();
+        match self.compile_remote(state, &task, name, &addr) {
             Ok(response) => match response {
                 CompileResponse::Success(output) => Ok(output),
                 CompileResponse::Err(err) => Err(err.into()),
             },
             Err(e) => {
                 trace!("Fallback to local build: {}", e);
+                state.statistic.inc_remote_fallback();
                 self.local.run_compile(state, task)
             }
         }
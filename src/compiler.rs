@@ -1,3 +1,4 @@
+use std::borrow::Cow;
 use std::cmp::max;
 use std::collections::hash_map;
 use std::collections::HashMap;
@@ -8,9 +9,10 @@ use std::iter::FromIterator;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Output};
 use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use ipc::Semaphore;
+use log::warn;
 use os_str_bytes::OsStrBytes;
 use path_absolutize::Absolutize;
 use serde::{Deserialize, Serialize};
@@ -18,12 +20,18 @@ use sha2::{Digest, Sha256};
 use tempfile::{NamedTempFile, TempDir};
 use thiserror::Error;
 
-use crate::cache::{Cache, FileHasher};
+use crate::basedirs::BaseDirs;
+use crate::cache::{Cache, EntryMeta, FileHasher};
 use crate::cmd;
 use crate::compiler::CompileInput::{Preprocessed, Source};
 use crate::config::Config;
+use crate::direct::{included_files, DirectKey};
+use crate::durations::TaskDurations;
+use crate::explain::{ExplainLog, KeyRecord};
 use crate::io::memstream::MemStream;
-use crate::io::statistic::Statistic;
+use crate::io::statistic::{Statistic, StatisticFile, Uncacheable};
+use crate::trace;
+use crate::trace::Trace;
 use crate::utils::OsStrExt;
 
 #[derive(Error, Debug)]
@@ -156,6 +164,15 @@ pub struct SharedState {
     pub semaphore: Semaphore,
     pub cache: Cache,
     pub statistic: Statistic,
+    base_dirs: BaseDirs,
+    // Cache namespace, mixed into cache keys.
+    namespace: Option<String>,
+    explain_log: Option<ExplainLog>,
+    pub task_durations: TaskDurations,
+    pub trace: Option<Arc<Trace>>,
+    statistic_file: StatisticFile,
+    statistic_json: Option<PathBuf>,
+    direct_mode: bool,
     pub temp_dir: TempDir,
     use_response_files: bool,
 }
@@ -168,13 +185,44 @@ impl SharedState {
         let semaphore = Semaphore::new("octobuild-worker", max(config.process_limit, 1_usize))?;
         Ok(SharedState {
             semaphore,
-            cache: Cache::new(config),
+            cache: Cache::new(config)?,
             statistic: Statistic::new(),
+            base_dirs: BaseDirs::new(&config.base_dirs),
+            namespace: config.cache_namespace.clone(),
+            explain_log: config.explain.then(|| ExplainLog::new(&config.cache)),
+            task_durations: TaskDurations::new(&config.cache),
+            trace: config
+                .trace_json
+                .as_ref()
+                .map(|path| Arc::new(Trace::new(path))),
+            statistic_file: StatisticFile::new(&config.cache),
+            statistic_json: config.statistic_json.clone(),
+            direct_mode: config.direct_mode,
             temp_dir: tempfile::Builder::new().prefix("octobuild").tempdir()?,
             use_response_files: config.use_response_files,
         })
     }
 
+    // Add statistic of this process to cumulative one.
+    pub fn save_statistic(&self) {
+        if let Err(e) = self.statistic_file.add(&self.statistic) {
+            warn!("Can't save cache statistic: {}", e);
+        }
+    }
+
+    // Write statistic of this process as JSON if requested by configuration.
+    pub fn write_statistic_json(&self) {
+        if let Some(path) = &self.statistic_json {
+            if let Err(e) = self
+                .statistic
+                .to_json()
+                .and_then(|json| Ok(std::fs::write(path, json)?))
+            {
+                warn!("Can't write statistic to {}: {}", path.display(), e);
+            }
+        }
+    }
+
     pub fn wrap_slow<T, F: FnOnce() -> T>(&self, func: F) -> T {
         let guard = self.semaphore.access();
         let result = func();
@@ -320,7 +368,7 @@ impl CommandInfo {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct OutputInfo {
     pub status: Option<i32>,
     pub stdout: Vec<u8>,
@@ -448,6 +496,28 @@ pub struct CompilationTask {
     pub output_object: PathBuf,
 }
 
+impl CompilationTask {
+    // Files produced by task.
+    #[must_use]
+    pub fn outputs(&self) -> Vec<PathBuf> {
+        let mut outputs: Vec<PathBuf> = vec![self.output_object.clone()];
+        if let Some(path) = self.shared.pch_usage.get_out_abs() {
+            outputs.push(path.clone());
+        }
+        for path in &outputs {
+            assert!(path.is_absolute());
+        }
+        outputs
+    }
+
+    fn entry_meta(&self, toolchain: Option<String>) -> EntryMeta {
+        EntryMeta {
+            toolchain,
+            source: Some(self.input_source.display().to_string()),
+        }
+    }
+}
+
 pub struct SourceInput {
     pub path: PathBuf,
     pub current_dir: Option<PathBuf>,
@@ -520,6 +590,15 @@ impl CompilerOutput {
         }
     }
 
+    // Content as single buffer, copied only if it is stored in chunks.
+    #[must_use]
+    pub fn to_bytes(&self) -> Cow<'_, [u8]> {
+        match self {
+            CompilerOutput::MemSteam(v) => Cow::Owned(From::from(v)),
+            CompilerOutput::Vec(v) => Cow::Borrowed(v),
+        }
+    }
+
     pub fn to_vec(&self) -> Vec<u8> {
         match self {
             CompilerOutput::MemSteam(v) => From::from(v),
@@ -564,18 +643,34 @@ pub trait Toolchain: Send + Sync {
         state: &SharedState,
         task: &CompilationTask,
     ) -> crate::Result<OutputInfo> {
+        state.statistic.inc_cache_mode(state.cache.mode());
+        let direct = self.direct_key(state, task).map(DirectKey::new);
+        if let Some(direct) = &direct {
+            if let Some(output) = self.run_direct(state, task, &direct.key) {
+                return Ok(output);
+            }
+        }
+        let start_time = Instant::now();
+        let span = trace::span("preprocess");
         let preprocessed = self.run_preprocess(state, task)?;
+        drop(span);
+        state.statistic.add_preprocess_time(start_time.elapsed());
         match preprocessed {
             PreprocessResult::Success(preprocessed) => {
-                self.run_compile_cached(state, task, preprocessed)
+                self.run_compile_cached(state, task, preprocessed, direct.as_ref())
+            }
+            PreprocessResult::Failed(output) => {
+                state
+                    .statistic
+                    .inc_uncacheable(Uncacheable::PreprocessFailed);
+                Ok(OutputInfo {
+                    status: output.status,
+                    // Preprocessor stdout contains the whole preprocessed file.
+                    // We don't want to print all of that to the user.
+                    stdout: Vec::new(),
+                    stderr: output.stderr,
+                })
             }
-            PreprocessResult::Failed(output) => Ok(OutputInfo {
-                status: output.status,
-                // Preprocessor stdout contains the whole preprocessed file.
-                // We don't want to print all of that to the user.
-                stdout: Vec::new(),
-                stderr: output.stderr,
-            }),
         }
     }
 
@@ -584,54 +679,195 @@ pub trait Toolchain: Send + Sync {
         state: &SharedState,
         task: &CompilationTask,
         preprocessed: CompilerOutput,
+        direct: Option<&DirectKey>,
     ) -> crate::Result<OutputInfo> {
         let mut hasher = Sha256::new();
+        // Components of cache key for explain mode
+        let mut components = state.explain_log.as_ref().map(|_| Vec::new());
+        // Preprocessed data is collected to single buffer only if it is needed as a whole.
+        let content = (!state.base_dirs.is_empty() || components.is_some() || direct.is_some())
+            .then(|| preprocessed.to_bytes());
+        let rewritten = content
+            .as_deref()
+            .map(|content| state.base_dirs.rewrite_preprocessed(content));
         // Get hash from preprocessed data
-        hasher.hash_u64(preprocessed.len() as u64);
-        preprocessed.copy(&mut hasher)?;
+        match rewritten.as_deref() {
+            Some(rewritten) => {
+                hasher.hash_bytes(rewritten);
+                push_component(&mut components, "preprocessed", || {
+                    hex::encode(Sha256::digest(rewritten))
+                });
+            }
+            None => {
+                hasher.hash_u64(preprocessed.len() as u64);
+                preprocessed.copy(&mut hasher)?;
+            }
+        }
 
-        if let Some(identifier) = self.identifier() {
-            hasher.hash_str(&identifier);
+        let identifier = self.identifier();
+        if let Some(identifier) = &identifier {
+            hasher.hash_str(identifier);
+        }
+        push_component(&mut components, "identifier", || {
+            identifier.clone().unwrap_or_default()
+        });
+        // Keys of shared cache don't depend on namespace setting.
+        if let Some(namespace) = &state.namespace {
+            hasher.hash_str(namespace);
+            push_component(&mut components, "namespace", || namespace.clone());
         }
 
+        let included = match (direct, content.as_deref()) {
+            (Some(direct), Some(content)) => match included_files(content, &task.shared.command) {
+                Ok(included) => Some(included),
+                Err(e) => {
+                    // Compilation is still cached, only without direct mode manifest.
+                    warn!("Can't collect included files of {}: {}", direct.key, e);
+                    None
+                }
+            },
+            _ => None,
+        };
+        // Buffers borrow preprocessed output, which is moved to compile step.
+        drop(rewritten);
+        drop(content);
+
         let step = self.create_compile_step(task, preprocessed);
 
         // Hash arguments
         hasher.hash_u64(step.args.len() as u64);
-        for arg in &step.args {
-            hasher.hash_os_string(arg)
+        for (index, arg) in step.args.iter().enumerate() {
+            let arg = state.base_dirs.rewrite_arg(arg);
+            hasher.hash_os_string(&arg);
+            push_component(&mut components, format!("arg {index}"), || {
+                arg.to_string_lossy().into_owned()
+            });
         }
         // Hash input files
         match &step.pch_usage.get_in_abs() {
             Some(path) => {
                 assert!(path.is_absolute());
-                hasher.hash_str(&state.cache.file_hash(path)?.hash);
+                let hash = state.cache.file_hash(path)?.hash;
+                hasher.hash_str(&hash);
+                push_component(&mut components, "pch", || hash);
             }
             None => {
                 hasher.hash_u64(0);
+                push_component(&mut components, "pch", || "-".to_string());
             }
         }
         // Store output precompiled flag
         hasher.hash_u8(u8::from(step.pch_usage.is_out()));
+        push_component(&mut components, "pch_out", || {
+            step.pch_usage.is_out().to_string()
+        });
 
-        // Output files list
-        let mut outputs: Vec<PathBuf> = Vec::new();
-        if let Some(path) = &step.output_object {
-            assert!(path.is_absolute());
-            outputs.push(path.clone());
+        // Try to get files from cache or run
+        let hash = hex::encode(hasher.finalize());
+        if let (Some(explain_log), Some(components)) = (&state.explain_log, components) {
+            if let Err(e) = task
+                .shared
+                .command
+                .absolutize(&task.input_source)
+                .and_then(|source| {
+                    explain_log.record(&source, KeyRecord::new(hash.clone(), components))
+                })
+            {
+                warn!("Can't record cache key components: {}", e);
+            }
         }
-        if let Some(path) = step.pch_usage.get_out_abs() {
-            assert!(path.is_absolute());
-            outputs.push(path.clone());
+        let meta = task.entry_meta(identifier);
+        let output = state.cache.run_file_cached(
+            &state.statistic,
+            &hash,
+            &meta,
+            task.outputs(),
+            || -> crate::Result<OutputInfo> {
+                let start_time = Instant::now();
+                let _span = trace::span("compile");
+                let output = self.run_compile(state, step);
+                state.statistic.add_compile_time(start_time.elapsed());
+                output
+            },
+        )?;
+        if let (Some(direct), Some(included)) = (direct, included) {
+            if output.success() {
+                store_manifest(state, direct, &meta, included, &hash);
+            }
         }
+        Ok(output)
+    }
 
-        // Try to get files from cache or run
-        state.cache.run_file_cached(
+    // Compute direct mode key from source file, arguments and toolchain (None - direct mode is not used).
+    fn direct_key(&self, state: &SharedState, task: &CompilationTask) -> Option<String> {
+        // Dependency file is produced by preprocessor.
+        if !state.direct_mode || task.shared.deps_file.is_some() {
+            return None;
+        }
+        let mut hasher = Sha256::new();
+        hasher.hash_str("direct");
+        hasher.hash_str(&self.identifier()?);
+        if let Some(namespace) = &state.namespace {
+            hasher.hash_str(namespace);
+        }
+        hasher.hash_str(&task.language);
+        hasher.hash_os_string(task.input_source.as_os_str());
+        hasher.hash_str(&state.cache.file_hash(&task.input_source).ok()?.hash);
+        match &task.shared.command.current_dir {
+            Some(dir) => hasher.hash_os_string(dir.as_os_str()),
+            None => hasher.hash_u64(0),
+        }
+        hasher.hash_u64(task.shared.args.len() as u64);
+        for arg in &task.shared.args {
+            hasher.hash_arg(arg);
+        }
+        // Include search paths may be passed via environment.
+        for name in ["INCLUDE", "CPATH", "C_INCLUDE_PATH", "CPLUS_INCLUDE_PATH"] {
+            hasher.hash_str(task.shared.command.env.get(name).unwrap_or_default());
+        }
+        match task.shared.pch_usage.get_in_abs() {
+            Some(path) => hasher.hash_str(&state.cache.file_hash(path).ok()?.hash),
+            None => hasher.hash_u64(0),
+        }
+        hasher.hash_u8(u8::from(task.shared.run_second_cpp));
+        Some(hex::encode(hasher.finalize()))
+    }
+
+    // Restore task outputs by include manifest without preprocessing.
+    fn run_direct(
+        &self,
+        state: &SharedState,
+        task: &CompilationTask,
+        key: &str,
+    ) -> Option<OutputInfo> {
+        let manifest = state.cache.load_manifest(key)?;
+        let hash = manifest.find(&state.cache)?;
+        let output = state.cache.read_cached(
             &state.statistic,
-            &hex::encode(hasher.finalize()),
-            outputs,
-            || -> crate::Result<OutputInfo> { self.run_compile(state, step) },
-        )
+            hash,
+            &task.entry_meta(self.identifier()),
+            &task.outputs(),
+        )?;
+        state.statistic.inc_direct_hit();
+        trace::annotate("cache", "direct hit");
+        Some(output)
+    }
+}
+
+// Add include files of compiled task to its manifest.
+fn store_manifest(
+    state: &SharedState,
+    direct: &DirectKey,
+    meta: &EntryMeta,
+    included: Vec<PathBuf>,
+    hash: &str,
+) {
+    let mut manifest = state.cache.load_manifest(&direct.key).unwrap_or_default();
+    if !manifest.add(&state.cache, included, hash, direct.started) {
+        return;
+    }
+    if let Err(e) = state.cache.store_manifest(&direct.key, meta, &manifest) {
+        warn!("Can't store manifest {}: {}", direct.key, e);
     }
 }
 
@@ -688,10 +924,61 @@ trait Hasher: Digest {
     fn hash_os_string(&mut self, str: &OsStr) {
         self.hash_bytes(str.to_raw_bytes().as_ref());
     }
+
+    // Hash argument by its fields, output arguments don't affect compilation result.
+    fn hash_arg(&mut self, arg: &Arg) {
+        let scope_tag = |scope: &Scope| match scope {
+            Scope::Preprocessor => 0,
+            Scope::Compiler => 1,
+            Scope::Shared => 2,
+            Scope::Ignore => 3,
+        };
+        match arg {
+            Arg::Flag { scope, flag } => {
+                self.hash_u8(0);
+                self.hash_u8(scope_tag(scope));
+                self.hash_str(flag);
+            }
+            Arg::Param {
+                scope,
+                flag,
+                value,
+                spaceable,
+            } => {
+                self.hash_u8(1);
+                self.hash_u8(scope_tag(scope));
+                self.hash_str(flag);
+                self.hash_str(value);
+                self.hash_u8(u8::from(*spaceable));
+            }
+            Arg::Input { kind, flag, file } => {
+                self.hash_u8(2);
+                self.hash_u8(match kind {
+                    InputKind::Source => 0,
+                    InputKind::Marker => 1,
+                    InputKind::Precompiled => 2,
+                });
+                self.hash_str(flag);
+                self.hash_str(file);
+            }
+            Arg::Output { .. } => self.hash_u8(3),
+        }
+    }
 }
 
 impl<D: Digest + ?Sized> Hasher for D {}
 
+// Remember cache key component if explain mode is enabled.
+fn push_component<N, V>(components: &mut Option<Vec<(String, String)>>, name: N, value: V)
+where
+    N: Into<String>,
+    V: FnOnce() -> String,
+{
+    if let Some(components) = components {
+        components.push((name.into(), value()));
+    }
+}
+
 pub struct ToolchainCompilationTask {
     pub toolchain: Arc<dyn Toolchain>,
     pub task: CompilationTask,
@@ -1,19 +1,108 @@
 use figment::providers::{Env, Format, Serialized, Yaml};
 use figment::Figment;
+use std::collections::BTreeMap;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::path::PathBuf;
 use std::sync::OnceLock;
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheBackendKind {
+    // Local directory (see `cache`).
+    File,
+    // Remote HTTP server (see `cache_remote_url`).
+    Http,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheRemoteMode {
+    // Only download entries.
+    Read,
+    // Download and upload entries.
+    ReadWrite,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheCompression {
+    None,
+    Lz4,
+    Zstd,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheMode {
+    // Read and write cache entries.
+    Normal,
+    // Only read cache entries.
+    ReadOnly,
+    // Always compile and overwrite cache entries.
+    Recache,
+    // Don't use cache at all.
+    Disabled,
+}
+
+impl CacheMode {
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            CacheMode::Normal => "normal",
+            CacheMode::ReadOnly => "readonly",
+            CacheMode::Recache => "recache",
+            CacheMode::Disabled => "disabled",
+        }
+    }
+
+    // Whether existing cache entries are used.
+    #[must_use]
+    pub fn reads(self) -> bool {
+        matches!(self, CacheMode::Normal | CacheMode::ReadOnly)
+    }
+
+    // Whether new cache entries are stored.
+    #[must_use]
+    pub fn writes(self) -> bool {
+        matches!(self, CacheMode::Normal | CacheMode::Recache)
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct Config {
+    // Directories replaced by relative paths when computing cache keys.
+    pub base_dirs: Vec<PathBuf>,
     pub cache: PathBuf,
+    pub cache_backend: CacheBackendKind,
     pub cache_limit_mb: u64,
+    pub cache_mode: CacheMode,
+    pub cache_compression: CacheCompression,
     pub cache_compression_level: u32,
+    // Store failed compilations too, so the same errors are replayed without compiling.
+    pub cache_failures: bool,
+    // Count of background threads writing cache entries (0 - write on compilation thread).
+    pub cache_writers: usize,
+    // Partition of local cache with separate entries and size limit (None - shared cache).
+    pub cache_namespace: Option<String>,
+    // Size limits of namespaces overriding `cache_limit_mb`.
+    pub cache_namespace_limits_mb: BTreeMap<String, u64>,
+    pub cache_remote_url: Option<url::Url>,
+    pub cache_remote_mode: CacheRemoteMode,
     pub coordinator: Option<url::Url>,
     pub coordinator_bind: SocketAddr,
+    // Look up cache entries by include manifest without running preprocessor.
+    pub direct_mode: bool,
+    // Record cache key components to explain cache misses (see `octo_cache explain`).
+    pub explain: bool,
     pub helper_bind: SocketAddr,
+    // Continue building tasks not depending on failed ones (see `/keepgoing` flag).
+    pub keep_going: bool,
     pub process_limit: usize,
     pub run_second_cpp: bool,
+    // File to write build statistic in JSON format.
+    pub statistic_json: Option<PathBuf>,
+    // File to write build timeline in Chrome trace format.
+    pub trace_json: Option<PathBuf>,
     pub use_response_files: bool,
 }
 
@@ -32,14 +121,29 @@ const DEFAULT_USE_RESPONSE_FILES: bool = false;
 impl Default for Config {
     fn default() -> Self {
         Self {
+            base_dirs: Vec::new(),
             cache: project_dirs().cache_dir().into(),
+            cache_backend: CacheBackendKind::File,
             cache_limit_mb: 64 * 1024,
+            cache_mode: CacheMode::Normal,
+            cache_compression: CacheCompression::Lz4,
             cache_compression_level: 1,
+            cache_failures: false,
+            cache_writers: 0,
+            cache_namespace: None,
+            cache_namespace_limits_mb: BTreeMap::new(),
+            cache_remote_url: None,
+            cache_remote_mode: CacheRemoteMode::Read,
             coordinator: None,
             coordinator_bind: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 3000)),
+            direct_mode: false,
+            explain: false,
             helper_bind: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0)),
+            keep_going: false,
             process_limit: num_cpus::get(),
             run_second_cpp: true,
+            statistic_json: None,
+            trace_json: None,
             use_response_files: DEFAULT_USE_RESPONSE_FILES,
         }
     }
@@ -56,14 +160,34 @@ impl Config {
             figment = figment.merge(Yaml::file(path));
         }
 
-        Ok(figment.merge(Env::prefixed("OCTOBUILD_")).extract()?)
+        let config: Config = figment.merge(Env::prefixed("OCTOBUILD_")).extract()?;
+        if let Some(namespace) = &config.cache_namespace {
+            // Namespace is used as a directory name.
+            if !is_valid_namespace(namespace) {
+                return Err(crate::Error::Generic(format!(
+                    "Invalid cache namespace: {namespace}"
+                )));
+            }
+        }
+        Ok(config)
+    }
+
+    // Local cache size limit of given namespace in megabytes.
+    #[must_use]
+    pub fn namespace_limit_mb(&self, namespace: Option<&str>) -> u64 {
+        namespace
+            .and_then(|namespace| self.cache_namespace_limits_mb.get(namespace))
+            .copied()
+            .unwrap_or(self.cache_limit_mb)
     }
 
     pub fn print_help(&self, executable: &str) {
         println!();
         println!("Usage:");
-        println!("  {} <file>", executable);
+        println!("  {} <file> [/keepgoing]", executable);
         println!("  {} /reset", executable);
+        println!("  {} /stats", executable);
+        println!("  {} /zerostats", executable);
         println!();
         println!("Octobuild configuration:");
         println!(
@@ -89,6 +213,15 @@ impl Config {
     }
 }
 
+#[must_use]
+pub fn is_valid_namespace(namespace: &str) -> bool {
+    !namespace.is_empty()
+        && !namespace.starts_with('.')
+        && namespace
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
 fn local_config_path() -> Option<PathBuf> {
     Some(project_dirs().config_dir().join("octobuild.conf"))
 }
@@ -0,0 +1,227 @@
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cache::FileHasher;
+use crate::compiler::CommandInfo;
+use crate::io::binary::read_exact;
+use crate::linemarker;
+
+const MANIFEST_HEADER: &[u8] = b"OBMF\x00\x01";
+// Count of include file sets stored per manifest.
+const MANIFEST_ENTRIES: usize = 16;
+// Files modified shortly before preprocessing are not trusted due to coarse timestamps.
+const MODIFIED_MARGIN: Duration = Duration::from_secs(2);
+// Macros expanded to build time, so result of preprocessing depends on more than file content.
+const TIME_MACROS: [&[u8]; 3] = [b"__DATE__", b"__TIME__", b"__TIMESTAMP__"];
+
+// Direct mode lookup key of compilation task.
+pub struct DirectKey {
+    pub key: String,
+    // Time before preprocessing was started.
+    pub started: SystemTime,
+}
+
+// Include manifest of direct mode.
+//
+// Maps content of source file and all headers included by it to cache key of compilation result,
+// so the result can be found without running preprocessor.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Manifest {
+    entries: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ManifestEntry {
+    // Included files with their content hashes.
+    files: Vec<(PathBuf, String)>,
+    // Cache key of compilation result.
+    result: String,
+}
+
+impl DirectKey {
+    #[must_use]
+    pub fn new(key: String) -> Self {
+        DirectKey {
+            key,
+            started: SystemTime::now(),
+        }
+    }
+}
+
+impl Manifest {
+    pub fn read(mut stream: impl Read) -> crate::Result<Self> {
+        if read_exact(&mut stream, MANIFEST_HEADER.len())? != MANIFEST_HEADER {
+            return Err(crate::Error::Generic("invalid manifest header".to_string()));
+        }
+        Ok(bincode::deserialize_from(stream)?)
+    }
+
+    pub fn write(&self, mut stream: impl Write) -> crate::Result<()> {
+        stream.write_all(MANIFEST_HEADER)?;
+        bincode::serialize_into(stream, self)?;
+        Ok(())
+    }
+
+    // Find cache key matching current content of included files.
+    pub fn find(&self, hasher: &dyn FileHasher) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|entry| {
+                entry.files.iter().all(|(path, hash)| {
+                    hasher
+                        .file_hash(path)
+                        .is_ok_and(|actual| actual.hash == *hash)
+                })
+            })
+            .map(|entry| entry.result.as_str())
+    }
+
+    // Remember cache key for given content of included files.
+    // Returns false if some of files can't be trusted or use time macros.
+    pub fn add(
+        &mut self,
+        hasher: &dyn FileHasher,
+        files: Vec<PathBuf>,
+        result: &str,
+        started: SystemTime,
+    ) -> bool {
+        let mut hashed = Vec::with_capacity(files.len());
+        for path in files {
+            match hasher.file_hash(&path) {
+                Ok(hash) if hash.modified + MODIFIED_MARGIN < started => {
+                    hashed.push((path, hash.hash));
+                }
+                _ => return false,
+            }
+        }
+        // Unchanged manifest is not rewritten.
+        if self
+            .entries
+            .first()
+            .is_some_and(|entry| entry.files == hashed && entry.result == result)
+        {
+            return false;
+        }
+        if hashed.iter().any(|(path, _)| uses_time_macros(path)) {
+            return false;
+        }
+        self.entries.retain(|entry| entry.files != hashed);
+        self.entries.insert(
+            0,
+            ManifestEntry {
+                files: hashed,
+                result: result.to_string(),
+            },
+        );
+        self.entries.truncate(MANIFEST_ENTRIES);
+        true
+    }
+
+    #[must_use]
+    pub fn files(&self) -> Vec<&PathBuf> {
+        let mut files: Vec<&PathBuf> = self
+            .entries
+            .iter()
+            .flat_map(|entry| entry.files.iter().map(|(path, _)| path))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        files.sort();
+        files
+    }
+}
+
+// Check if file mentions time macros (unreadable file is treated as using them).
+fn uses_time_macros(path: &Path) -> bool {
+    let Ok(content) = std::fs::read(path) else {
+        return true;
+    };
+    content.iter().enumerate().any(|(index, c)| {
+        *c == b'_'
+            && TIME_MACROS
+                .iter()
+                .any(|name| content[index..].starts_with(name))
+    })
+}
+
+// Collect files referenced by line markers of preprocessed output.
+pub fn included_files(preprocessed: &[u8], command: &CommandInfo) -> crate::Result<Vec<PathBuf>> {
+    let mut seen = HashSet::new();
+    let mut files = Vec::new();
+    for name in linemarker::file_names(preprocessed) {
+        // Skip pseudo files like <built-in> and <command line>.
+        if name.is_empty() || name.starts_with('<') || !seen.insert(name.clone()) {
+            continue;
+        }
+        files.push(command.absolutize(&PathBuf::from(name))?);
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::{Duration, SystemTime};
+
+    use crate::cache::Cache;
+    use crate::compiler::CommandInfo;
+    use crate::config::Config;
+    use crate::direct::{included_files, Manifest};
+
+    #[test]
+    #[cfg(unix)]
+    fn test_included_files() {
+        let command = CommandInfo {
+            current_dir: Some(PathBuf::from("/work")),
+            ..CommandInfo::simple(PathBuf::from("clang"))
+        };
+        let preprocessed = b"# 1 \"main.cpp\"\n# 1 \"<built-in>\" 1\n#line 1 \"C:\\\\include\\\\a.h\"\n  # 2 \"/usr/include/b.h\" 1 3\nint x;\n# 5 \"main.cpp\" 2\n";
+        assert_eq!(
+            included_files(preprocessed, &command).unwrap(),
+            vec![
+                PathBuf::from("/work/main.cpp"),
+                PathBuf::from("/work/C:\\include\\a.h"),
+                PathBuf::from("/usr/include/b.h"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_manifest() {
+        let temp = tempfile::tempdir().unwrap();
+        let cache = Cache::new(&Config {
+            cache: temp.path().join("cache"),
+            ..Config::default()
+        })
+        .unwrap();
+        let header = temp.path().join("a.h");
+        fs::write(&header, "1").unwrap();
+        let started = SystemTime::now() + Duration::from_secs(10);
+
+        let mut manifest = Manifest::default();
+        assert!(manifest.add(&cache, vec![header.clone()], "one", started));
+        let mut data = Vec::new();
+        manifest.write(&mut data).unwrap();
+        let mut manifest = Manifest::read(data.as_slice()).unwrap();
+        assert_eq!(manifest.find(&cache), Some("one"));
+        // Same entry is already first, nothing to store.
+        assert!(!manifest.add(&cache, vec![header.clone()], "one", started));
+        assert!(manifest.add(&cache, vec![header.clone()], "other", started));
+        assert_eq!(manifest.find(&cache), Some("other"));
+
+        // Header is modified after preprocessing start.
+        let mut manifest = Manifest::default();
+        assert!(!manifest.add(&cache, vec![header], "two", SystemTime::now()));
+
+        // Result depends on build time.
+        let source = temp.path().join("version.cpp");
+        fs::write(&source, "const char* built = __DATE__;").unwrap();
+        let mut manifest = Manifest::default();
+        assert!(!manifest.add(&cache, vec![source], "three", started));
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Seek};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::io::cacheindex::unix_time;
+use crate::utils::{lock_file, replace_file};
+
+const DURATIONS_NAME: &str = "durations";
+// Count of remembered tasks, least recently built ones are forgotten first.
+const MAX_RECORDS: usize = 100_000;
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct DurationRecord {
+    millis: u64,
+    // Last build time (seconds since Unix epoch).
+    time: u64,
+}
+
+// Durations of previously built tasks, used to start long chains of build graph first.
+//
+// Stored in a single file of cache directory, keyed by hash of task title and command.
+pub struct TaskDurations {
+    path: PathBuf,
+}
+
+impl TaskDurations {
+    #[must_use]
+    pub fn new(cache_dir: &Path) -> Self {
+        TaskDurations {
+            path: cache_dir.join(DURATIONS_NAME),
+        }
+    }
+
+    #[must_use]
+    pub fn key(title: &str, command: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(title.as_bytes());
+        hasher.update([0]);
+        hasher.update(command.as_bytes());
+        hex::encode(hasher.finalize())
+    }
+
+    // Load remembered durations of all tasks.
+    pub fn load(&self) -> crate::Result<HashMap<String, Duration>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(read_records(&file)?
+            .into_iter()
+            .map(|(key, record)| (key, Duration::from_millis(record.millis)))
+            .collect())
+    }
+
+    // Merge durations of built tasks into remembered ones.
+    pub fn save(&self, durations: &HashMap<String, Duration>) -> crate::Result<()> {
+        if durations.is_empty() {
+            return Ok(());
+        }
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // File is replaced atomically, so readers don't need a lock.
+        let _lock = lock_file(&self.path)?;
+        let mut records = match File::open(&self.path) {
+            Ok(file) => read_records(&file)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let time = unix_time(SystemTime::now());
+        for (key, duration) in durations {
+            let millis = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+            // Average with previous duration to smooth out noise of a single build.
+            let millis = records
+                .get(key)
+                .map_or(millis, |record| record.millis / 2 + millis / 2);
+            records.insert(key.clone(), DurationRecord { millis, time });
+        }
+        if records.len() > MAX_RECORDS {
+            let mut times: Vec<u64> = records.values().map(|record| record.time).collect();
+            times.sort_unstable_by(|a, b| b.cmp(a));
+            let oldest = times[MAX_RECORDS - 1];
+            records.retain(|_, record| record.time >= oldest);
+        }
+        replace_file(&self.path, &bincode::serialize(&records)?)
+    }
+}
+
+// Empty or damaged file is treated as no history.
+fn read_records(mut file: &File) -> crate::Result<HashMap<String, DurationRecord>> {
+    file.rewind()?;
+    Ok(bincode::deserialize_from(BufReader::new(file)).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use crate::durations::TaskDurations;
+
+    #[test]
+    fn test_task_durations() {
+        let temp = tempfile::tempdir().unwrap();
+        let durations = TaskDurations::new(temp.path());
+        assert!(durations.load().unwrap().is_empty());
+
+        let first = TaskDurations::key("main.cpp", "cl.exe /c main.cpp");
+        let second = TaskDurations::key("util.cpp", "cl.exe /c util.cpp");
+        assert_ne!(first, second);
+        durations
+            .save(&HashMap::from([
+                (first.clone(), Duration::from_secs(10)),
+                (second.clone(), Duration::from_secs(1)),
+            ]))
+            .unwrap();
+        durations
+            .save(&HashMap::from([(first.clone(), Duration::from_secs(20))]))
+            .unwrap();
+
+        let loaded = durations.load().unwrap();
+        assert_eq!(loaded[&first], Duration::from_secs(15));
+        assert_eq!(loaded[&second], Duration::from_secs(1));
+    }
+}
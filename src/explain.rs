@@ -0,0 +1,202 @@
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{BufReader, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::io::cacheindex::unix_time;
+
+const EXPLAIN_DIR: &str = "explain";
+// Count of distinct cache keys remembered per source file.
+const RECORDS_PER_SOURCE: usize = 16;
+
+// Cache key of compilation together with all components it was computed from.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyRecord {
+    pub key: String,
+    // Compilation time (seconds since Unix epoch).
+    pub time: u64,
+    pub components: Vec<(String, String)>,
+}
+
+// Difference between key components of two compilations.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Change<'a> {
+    Changed {
+        name: &'a str,
+        old: &'a str,
+        new: &'a str,
+    },
+    Added {
+        name: &'a str,
+        value: &'a str,
+    },
+    Removed {
+        name: &'a str,
+        value: &'a str,
+    },
+}
+
+// Log of recent cache keys per source file, used to explain cache misses.
+//
+// Stored in cache directory, one file per source.
+pub struct ExplainLog {
+    dir: PathBuf,
+}
+
+impl KeyRecord {
+    #[must_use]
+    pub fn new(key: String, components: Vec<(String, String)>) -> Self {
+        KeyRecord {
+            key,
+            time: unix_time(SystemTime::now()),
+            components,
+        }
+    }
+
+    // Compare key components of previous compilation with this one.
+    #[must_use]
+    pub fn diff<'a>(&'a self, previous: &'a KeyRecord) -> Vec<Change<'a>> {
+        let mut changes = Vec::new();
+        for (name, old) in &previous.components {
+            match self.component(name) {
+                Some(new) if new == old => {}
+                Some(new) => changes.push(Change::Changed { name, old, new }),
+                None => changes.push(Change::Removed { name, value: old }),
+            }
+        }
+        for (name, value) in &self.components {
+            if previous.component(name).is_none() {
+                changes.push(Change::Added { name, value });
+            }
+        }
+        changes
+    }
+
+    fn component(&self, name: &str) -> Option<&str> {
+        self.components
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl Display for Change<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Change::Changed { name, old, new } => write!(f, "{name}: {old} -> {new}"),
+            Change::Added { name, value } => write!(f, "{name}: added {value}"),
+            Change::Removed { name, value } => write!(f, "{name}: removed {value}"),
+        }
+    }
+}
+
+impl ExplainLog {
+    #[must_use]
+    pub fn new(cache_dir: &Path) -> Self {
+        ExplainLog {
+            dir: cache_dir.join(EXPLAIN_DIR),
+        }
+    }
+
+    // Remember cache key of source file compilation.
+    pub fn record(&self, source: &Path, record: KeyRecord) -> crate::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.path(source))?;
+        file.lock_exclusive()?;
+        let mut records: Vec<KeyRecord> =
+            bincode::deserialize_from(BufReader::new(&file)).unwrap_or_default();
+        records.retain(|r| r.key != record.key);
+        records.push(record);
+        let skip = records.len().saturating_sub(RECORDS_PER_SOURCE);
+        let data = bincode::serialize(&records[skip..])?;
+        file.set_len(0)?;
+        file.rewind()?;
+        file.write_all(&data)?;
+        Ok(())
+    }
+
+    // Recorded cache keys of source file, oldest first.
+    pub fn load(&self, source: &Path) -> crate::Result<Vec<KeyRecord>> {
+        let file = match fs::File::open(self.path(source)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        file.lock_shared()?;
+        Ok(bincode::deserialize_from(BufReader::new(&file))?)
+    }
+
+    fn path(&self, source: &Path) -> PathBuf {
+        let hash = Sha256::digest(source.to_string_lossy().as_bytes());
+        self.dir.join(hex::encode(hash))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::explain::{Change, ExplainLog, KeyRecord};
+
+    fn components(values: &[(&str, &str)]) -> Vec<(String, String)> {
+        values
+            .iter()
+            .map(|(name, value)| ((*name).to_string(), (*value).to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_explain_log() {
+        let temp = tempfile::tempdir().unwrap();
+        let log = ExplainLog::new(temp.path());
+        let source = Path::new("/work/main.cpp");
+        assert!(log.load(source).unwrap().is_empty());
+
+        let first = KeyRecord::new(
+            "aa".to_string(),
+            components(&[("arg 0", "-O2"), ("arg 1", "-g")]),
+        );
+        let second = KeyRecord::new(
+            "bb".to_string(),
+            components(&[("arg 0", "-O3"), ("arg 2", "-c")]),
+        );
+        log.record(source, first.clone()).unwrap();
+        log.record(source, second).unwrap();
+        log.record(source, first).unwrap();
+
+        let records = log.load(source).unwrap();
+        assert_eq!(
+            records.iter().map(|r| r.key.as_str()).collect::<Vec<_>>(),
+            vec!["bb", "aa"]
+        );
+        assert_eq!(
+            records[1].diff(&records[0]),
+            vec![
+                Change::Changed {
+                    name: "arg 0",
+                    old: "-O3",
+                    new: "-O2"
+                },
+                Change::Removed {
+                    name: "arg 2",
+                    value: "-c"
+                },
+                Change::Added {
+                    name: "arg 1",
+                    value: "-g"
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use fs2::FileExt;
+use log::warn;
+
+const IN_FLIGHT_DIR: &str = "inflight";
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// Waiting for other process holds a build worker, so long compilations are done twice
+// rather than blocking the build.
+const WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Compilations = Mutex<HashMap<String, Arc<Compilation>>>;
+
+// Registry of cache keys being compiled, so identical compilations wait for the first one
+// and take its result from cache instead of compiling the same again.
+//
+// Compilations of this process are tracked in memory, compilations of other processes
+// are detected by lock files in cache directory.
+pub struct InFlight {
+    dir: PathBuf,
+    compilations: Arc<Compilations>,
+}
+
+#[derive(Default)]
+struct Compilation {
+    done: Mutex<bool>,
+    condvar: Condvar,
+}
+
+// Registration of cache key, held until compilation result is written to cache.
+pub struct InFlightGuard {
+    key: String,
+    compilations: Arc<Compilations>,
+    compilation: Arc<Compilation>,
+    // Cross-process lock, released on close. Lock file is kept until cleanup.
+    _file: Option<File>,
+}
+
+impl InFlight {
+    #[must_use]
+    pub fn new(cache_dir: &Path) -> Self {
+        InFlight {
+            dir: cache_dir.join(IN_FLIGHT_DIR),
+            compilations: Arc::default(),
+        }
+    }
+
+    // Wait until nobody else compiles given key, then register key as compiled by caller.
+    // Also returns whether caller had to wait, so cache should be checked again.
+    pub fn acquire(&self, key: &str) -> (InFlightGuard, bool) {
+        let mut waited = false;
+        let compilation = loop {
+            let mut compilations = self.compilations.lock().unwrap();
+            match compilations.get(key) {
+                Some(compilation) => {
+                    let compilation = compilation.clone();
+                    drop(compilations);
+                    let done = compilation.done.lock().unwrap();
+                    drop(compilation.condvar.wait_while(done, |done| !*done));
+                    waited = true;
+                }
+                None => {
+                    let compilation = Arc::new(Compilation::default());
+                    compilations.insert(key.to_string(), compilation.clone());
+                    break compilation;
+                }
+            }
+        };
+        let path = self.dir.join(key.to_string() + ".lock");
+        let file = match lock_file(&self.dir, &path) {
+            Ok((file, contended)) => {
+                waited |= contended;
+                file
+            }
+            Err(e) => {
+                warn!("Can't lock in-flight compilation {}: {}", key, e);
+                None
+            }
+        };
+        let guard = InFlightGuard {
+            key: key.to_string(),
+            compilations: self.compilations.clone(),
+            compilation,
+            _file: file,
+        };
+        (guard, waited)
+    }
+
+    // Remove lock files of finished compilations.
+    // Lock files are not removed on release, as the file may be already opened by a waiting process.
+    pub fn cleanup(&self) -> crate::Result<()> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            let Ok(file) = OpenOptions::new().read(true).write(true).open(&path) else {
+                continue;
+            };
+            if file.try_lock_exclusive().is_ok() {
+                drop(fs::remove_file(&path));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Lock file, waiting while it is locked by another process.
+// Returns None if wait timed out. Also returns whether lock was contended.
+fn lock_file(dir: &Path, path: &Path) -> crate::Result<(Option<File>, bool)> {
+    fs::create_dir_all(dir)?;
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    let started = Instant::now();
+    let mut contended = false;
+    loop {
+        match file.try_lock_exclusive() {
+            Ok(()) => return Ok((Some(file), contended)),
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => contended = true,
+            Err(e) => return Err(e.into()),
+        }
+        if started.elapsed() > WAIT_TIMEOUT {
+            return Ok((None, true));
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.compilations.lock().unwrap().remove(&self.key);
+        *self.compilation.done.lock().unwrap() = true;
+        self.compilation.condvar.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    use crate::inflight::InFlight;
+
+    #[test]
+    fn test_in_flight() {
+        let temp = tempfile::tempdir().unwrap();
+        let in_flight = InFlight::new(temp.path());
+        let (guard, waited) = in_flight.acquire("0000");
+        assert!(!waited);
+        // Other keys are not blocked.
+        let (other, waited) = in_flight.acquire("1111");
+        assert!(!waited);
+        drop(other);
+
+        let released = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| {
+                let (_guard, waited) = in_flight.acquire("0000");
+                assert!(released.load(Ordering::Acquire));
+                waited
+            });
+            std::thread::sleep(Duration::from_millis(100));
+            released.store(true, Ordering::Release);
+            drop(guard);
+            assert!(waiter.join().unwrap());
+        });
+
+        // Compilation of another process.
+        let other_process = InFlight::new(temp.path());
+        let (guard, _) = other_process.acquire("2222");
+        std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| in_flight.acquire("2222").1);
+            std::thread::sleep(Duration::from_millis(100));
+            drop(guard);
+            assert!(waiter.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn test_in_flight_cleanup() {
+        let temp = tempfile::tempdir().unwrap();
+        let in_flight = InFlight::new(temp.path());
+        let lock_count = || fs::read_dir(temp.path().join("inflight")).unwrap().count();
+        drop(in_flight.acquire("0000"));
+        let (guard, _) = in_flight.acquire("1111");
+        assert_eq!(lock_count(), 2);
+
+        // Only lock files of finished compilations are removed.
+        in_flight.cleanup().unwrap();
+        assert_eq!(lock_count(), 1);
+        drop(guard);
+        in_flight.cleanup().unwrap();
+        assert_eq!(lock_count(), 0);
+    }
+}
@@ -26,6 +26,11 @@ pub fn write_u64(stream: &mut impl Write, i: u64) -> Result<()> {
     stream.write_u64::<LittleEndian>(i)
 }
 
+#[inline]
+pub fn write_u32(stream: &mut impl Write, i: u32) -> Result<()> {
+    stream.write_u32::<LittleEndian>(i)
+}
+
 #[inline]
 pub fn write_usize(stream: &mut impl Write, i: usize) -> Result<()> {
     write_u64(stream, i as u64)
@@ -36,6 +41,11 @@ pub fn read_u64(stream: &mut impl Read) -> Result<u64> {
     stream.read_u64::<LittleEndian>()
 }
 
+#[inline]
+pub fn read_u32(stream: &mut impl Read) -> Result<u32> {
+    stream.read_u32::<LittleEndian>()
+}
+
 #[inline]
 pub fn read_usize(stream: &mut impl Read) -> Result<usize> {
     Ok(read_u64(stream)? as usize)
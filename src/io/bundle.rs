@@ -0,0 +1,254 @@
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cache::{CacheBackend, CacheEntryWriter, EntryMeta};
+use crate::direct::Manifest;
+use crate::io::binary::read_exact;
+use crate::io::filecache::{
+    entry_blobs, inspect_cache, repack_cache, validate_hash, EntryFormat, FileCache,
+};
+
+const BUNDLE_HEADER: &[u8] = b"OBCB\x00\x01";
+
+// Archive of cache entries used to seed other caches (for example, fresh build agents).
+//
+// Bundle header is followed by records, each one is followed by packed entry of `size` bytes.
+// Entries are self-contained: blob content is packed into them. Records are terminated by None.
+pub struct BundleWriter<W: Write> {
+    stream: W,
+    format: EntryFormat,
+    count: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BundleRecord {
+    hash: String,
+    toolchain: Option<String>,
+    source: Option<String>,
+    size: u64,
+}
+
+// Result of bundle import.
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub imported: usize,
+    // Entries already present in target cache.
+    pub existing: usize,
+    // Entries failed verification.
+    pub damaged: usize,
+}
+
+// Entry size must be known before it is written to bundle, so repacked entries are written
+// to temporary file first.
+struct TempEntryWriter {
+    file: File,
+}
+
+impl<W: Write> BundleWriter<W> {
+    pub fn new(mut stream: W, format: EntryFormat) -> crate::Result<Self> {
+        stream.write_all(BUNDLE_HEADER)?;
+        Ok(BundleWriter {
+            stream,
+            format,
+            count: 0,
+        })
+    }
+
+    // Add local cache entry to bundle, returns false if entry is not found.
+    pub fn add(&mut self, cache: &FileCache, hash: &str, meta: &EntryMeta) -> crate::Result<bool> {
+        let Some(entry) = cache.open(hash)? else {
+            return Ok(false);
+        };
+        let has_blobs = !entry_blobs(hash, entry)?.is_empty();
+        let Some(entry) = cache.open(hash)? else {
+            return Ok(false);
+        };
+        // Entry is staged in temporary file, so failure to read it can't leave partial record in bundle.
+        let mut temp = tempfile::tempfile()?;
+        if has_blobs {
+            // Blobs are not shared between caches, so their content is packed into the entry.
+            let writer = TempEntryWriter {
+                file: temp.try_clone()?,
+            };
+            repack_cache(hash, entry, cache, Box::new(writer), &self.format)?;
+        } else {
+            std::io::copy(&mut (&entry), &mut temp)?;
+        }
+        temp.rewind()?;
+        let record = BundleRecord {
+            hash: hash.to_string(),
+            toolchain: meta.toolchain.clone(),
+            source: meta.source.clone(),
+            size: temp.metadata()?.len(),
+        };
+        bincode::serialize_into(&mut self.stream, &Some(&record))?;
+        if std::io::copy(&mut (&mut temp).take(record.size), &mut self.stream)? != record.size {
+            return Err(crate::Error::Generic(format!(
+                "Staged cache entry was truncated during export: {hash}"
+            )));
+        }
+        self.count += 1;
+        Ok(true)
+    }
+
+    // Write end of bundle, returns count of added entries.
+    pub fn finish(mut self) -> crate::Result<usize> {
+        bincode::serialize_into(&mut self.stream, &None::<BundleRecord>)?;
+        self.stream.flush()?;
+        Ok(self.count)
+    }
+}
+
+/// Verifies bundle entries and stores them into cache. Damaged entries are skipped.
+pub fn import_bundle(
+    mut stream: impl Read,
+    cache: &dyn CacheBackend,
+) -> crate::Result<ImportSummary> {
+    if read_exact(&mut stream, BUNDLE_HEADER.len())? != BUNDLE_HEADER {
+        return Err(crate::Error::Generic("invalid bundle header".to_string()));
+    }
+    let mut summary = ImportSummary::default();
+    while let Some(record) = bincode::deserialize_from::<_, Option<BundleRecord>>(&mut stream)? {
+        validate_hash(&record.hash)?;
+        let mut temp = tempfile::tempfile()?;
+        if std::io::copy(&mut (&mut stream).take(record.size), &mut temp)? != record.size {
+            return Err(crate::Error::Generic(
+                "unexpected end of bundle".to_string(),
+            ));
+        }
+        temp.rewind()?;
+        if inspect_cache(&record.hash, &temp, cache).is_err() && !is_manifest(&mut temp)? {
+            summary.damaged += 1;
+            continue;
+        }
+        let meta = EntryMeta {
+            toolchain: record.toolchain,
+            source: record.source,
+        };
+        let Some(mut writer) = cache.put(&record.hash, &meta)? else {
+            summary.existing += 1;
+            continue;
+        };
+        temp.rewind()?;
+        std::io::copy(&mut temp, &mut writer)?;
+        writer.commit()?;
+        summary.imported += 1;
+    }
+    Ok(summary)
+}
+
+// Direct mode manifests are stored along with cache entries.
+fn is_manifest(file: &mut File) -> crate::Result<bool> {
+    file.rewind()?;
+    Ok(Manifest::read(file).is_ok())
+}
+
+impl Write for TempEntryWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl CacheEntryWriter for TempEntryWriter {
+    fn commit(self: Box<Self>) -> crate::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::cache::{CacheBackend, EntryMeta};
+    use crate::compiler::OutputInfo;
+    use crate::config::{CacheCompression, Config};
+    use crate::direct::Manifest;
+    use crate::io::bundle::{import_bundle, BundleWriter};
+    use crate::io::filecache::{read_cache, write_cache, EntryFormat, FileCache};
+    use crate::io::statistic::Statistic;
+
+    #[test]
+    fn test_bundle() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: temp.path().join("source"),
+            cache_compression: CacheCompression::None,
+            ..Config::default()
+        };
+        let source = FileCache::new(&config);
+        let statistic = Statistic::new();
+        let path = temp.path().join("output.o");
+        // Large file is stored in blob.
+        let content = vec![7; 1024 * 1024];
+        fs::write(&path, &content).unwrap();
+        let meta = EntryMeta {
+            toolchain: Some("clang".to_string()),
+            source: Some("main.cpp".to_string()),
+        };
+        let writer = source.put("0000", &meta).unwrap().unwrap();
+        let output = OutputInfo {
+            status: Some(0),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        };
+        let format = EntryFormat::new(&config);
+        write_cache(&statistic, writer, vec![path.clone()], &output, &format).unwrap();
+
+        let mut data = Vec::new();
+        let mut bundle = BundleWriter::new(&mut data, format).unwrap();
+        assert!(bundle.add(&source, "0000", &meta).unwrap());
+        assert!(!bundle.add(&source, "1111", &meta).unwrap());
+        assert_eq!(bundle.finish().unwrap(), 1);
+
+        let target = FileCache::new(&Config {
+            cache: temp.path().join("target"),
+            ..Config::default()
+        });
+        let summary = import_bundle(data.as_slice(), &target).unwrap();
+        assert_eq!(summary.imported, 1);
+        assert_eq!(
+            target.entries().unwrap()["0000"].toolchain.as_deref(),
+            Some("clang")
+        );
+        fs::remove_file(&path).unwrap();
+        let entry = target.get("0000").unwrap().unwrap();
+        read_cache(
+            &statistic,
+            "0000",
+            entry,
+            std::slice::from_ref(&path),
+            &target,
+        )
+        .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), content);
+
+        let summary = import_bundle(data.as_slice(), &target).unwrap();
+        assert_eq!(summary.existing, 1);
+
+        // Direct mode manifest is not a packed entry, but is exported as well.
+        let mut writer = source.put("2222", &meta).unwrap().unwrap();
+        Manifest::default().write(&mut writer).unwrap();
+        writer.commit().unwrap();
+        let mut manifest = Vec::new();
+        let mut bundle = BundleWriter::new(&mut manifest, EntryFormat::new(&config)).unwrap();
+        assert!(bundle.add(&source, "2222", &meta).unwrap());
+        bundle.finish().unwrap();
+        let summary = import_bundle(manifest.as_slice(), &target).unwrap();
+        assert_eq!(summary.imported, 1);
+        assert!(target.contains("2222"));
+
+        // Last byte of entry belongs to its footer.
+        let position = data.len() - 2;
+        data[position] ^= 0xFF;
+        target.evict("0000").unwrap();
+        let summary = import_bundle(data.as_slice(), &target).unwrap();
+        assert_eq!(summary.damaged, 1);
+        assert!(!target.contains("0000"));
+    }
+}
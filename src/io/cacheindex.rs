@@ -0,0 +1,412 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use fs2::FileExt;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::cache::EntryMeta;
+
+const SNAPSHOT_NAME: &str = "index";
+const JOURNAL_NAME: &str = "index.journal";
+const SNAPSHOT_VERSION: u32 = 1;
+// Journal record consists of payload length, payload CRC32 and bincode payload.
+const RECORD_HEADER_SIZE: usize = 8;
+
+// Information about single cache entry.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub size: u64,
+    // Last access time (seconds since Unix epoch).
+    pub accessed: u64,
+    pub toolchain: Option<String>,
+    pub source: Option<String>,
+    // Referenced blobs with their stored sizes.
+    pub blobs: Vec<(String, u64)>,
+}
+
+pub type IndexEntries = HashMap<String, IndexEntry>;
+
+#[derive(Serialize, Deserialize)]
+struct IndexSnapshot {
+    version: u32,
+    entries: IndexEntries,
+}
+
+#[derive(Serialize, Deserialize)]
+enum IndexRecord {
+    Put {
+        hash: String,
+        size: u64,
+        accessed: u64,
+        toolchain: Option<String>,
+        source: Option<String>,
+        blobs: Vec<(String, u64)>,
+    },
+    Access {
+        hash: String,
+        accessed: u64,
+    },
+    Remove {
+        hash: String,
+    },
+}
+
+// Persistent index of cache entries used for LRU eviction.
+//
+// Index consists of snapshot file and append-only journal. Processes append small records
+// to the journal under exclusive lock, cleanup merges journal into a new snapshot.
+pub struct CacheIndex {
+    snapshot_path: PathBuf,
+    journal_path: PathBuf,
+}
+
+impl IndexRecord {
+    // Returns replaced or removed entry.
+    fn apply(self, entries: &mut IndexEntries) -> Option<IndexEntry> {
+        match self {
+            IndexRecord::Put {
+                hash,
+                size,
+                accessed,
+                toolchain,
+                source,
+                blobs,
+            } => entries.insert(
+                hash,
+                IndexEntry {
+                    size,
+                    accessed,
+                    toolchain,
+                    source,
+                    blobs,
+                },
+            ),
+            IndexRecord::Access { hash, accessed } => {
+                if let Some(entry) = entries.get_mut(&hash) {
+                    entry.accessed = accessed;
+                }
+                None
+            }
+            IndexRecord::Remove { hash } => entries.remove(&hash),
+        }
+    }
+}
+
+impl CacheIndex {
+    #[must_use]
+    pub fn new(dir: &Path) -> Self {
+        CacheIndex {
+            snapshot_path: dir.join(SNAPSHOT_NAME),
+            journal_path: dir.join(JOURNAL_NAME),
+        }
+    }
+
+    pub fn record_put(
+        &self,
+        hash: &str,
+        size: u64,
+        meta: &EntryMeta,
+        blobs: &[(String, u64)],
+    ) -> crate::Result<()> {
+        self.commit_put(hash, size, meta, blobs, || Ok(()))
+    }
+
+    // Record entry made visible by `commit`. Both run under index lock,
+    // so cleanup can't remove blobs referenced by the entry before it is recorded.
+    pub fn commit_put<C>(
+        &self,
+        hash: &str,
+        size: u64,
+        meta: &EntryMeta,
+        blobs: &[(String, u64)],
+        commit: C,
+    ) -> crate::Result<()>
+    where
+        C: FnOnce() -> crate::Result<()>,
+    {
+        let record = IndexRecord::Put {
+            hash: hash.to_string(),
+            size,
+            accessed: now(),
+            toolchain: meta.toolchain.clone(),
+            source: meta.source.clone(),
+            blobs: blobs.to_vec(),
+        };
+        let mut journal = self.open_journal()?;
+        journal.lock_exclusive()?;
+        commit()?;
+        write_record(&mut journal, &record)
+    }
+
+    pub fn record_access(&self, hash: &str) -> crate::Result<()> {
+        self.append(&IndexRecord::Access {
+            hash: hash.to_string(),
+            accessed: now(),
+        })
+    }
+
+    pub fn record_remove(&self, hash: &str) -> crate::Result<()> {
+        self.append(&IndexRecord::Remove {
+            hash: hash.to_string(),
+        })
+    }
+
+    // Size of not yet compacted journal.
+    #[must_use]
+    pub fn journal_size(&self) -> u64 {
+        fs::metadata(&self.journal_path).map_or(0, |stat| stat.len())
+    }
+
+    // Read current index state.
+    pub fn load<R>(&self, rebuild: R) -> crate::Result<IndexEntries>
+    where
+        R: FnOnce() -> crate::Result<IndexEntries>,
+    {
+        let journal = self.open_journal()?;
+        journal.lock_shared()?;
+        if let (Some(mut entries), Some(records)) = (self.read_snapshot(), read_journal(&journal)?)
+        {
+            apply_records(&mut entries, records);
+            return Ok(entries);
+        }
+        // Rebuild removes orphan blobs, so it needs exclusive lock.
+        journal.unlock()?;
+        journal.lock_exclusive()?;
+        Ok(self.read(&journal, rebuild)?.0)
+    }
+
+    // Modify index and store it as new snapshot.
+    //
+    // Function also receives blobs released by entries removed or replaced since last update.
+    pub fn update<R, F>(&self, rebuild: R, func: F) -> crate::Result<()>
+    where
+        R: FnOnce() -> crate::Result<IndexEntries>,
+        F: FnOnce(&mut IndexEntries, HashSet<String>) -> crate::Result<()>,
+    {
+        let journal = self.open_journal()?;
+        journal.lock_exclusive()?;
+        let (mut entries, released) = self.read(&journal, rebuild)?;
+        func(&mut entries, released)?;
+
+        let dir = self.snapshot_path.parent().unwrap();
+        let mut temp = tempfile::Builder::new()
+            .prefix(SNAPSHOT_NAME)
+            .tempfile_in(dir)?;
+        bincode::serialize_into(
+            &mut temp,
+            &IndexSnapshot {
+                version: SNAPSHOT_VERSION,
+                entries,
+            },
+        )?;
+        temp.persist(&self.snapshot_path)
+            .map_err(|e| crate::Error::IO(e.error))?;
+        journal.set_len(0)?;
+        Ok(())
+    }
+
+    // Must be called under exclusive lock, as rebuild removes orphan blobs.
+    fn read<R>(&self, journal: &File, rebuild: R) -> crate::Result<(IndexEntries, HashSet<String>)>
+    where
+        R: FnOnce() -> crate::Result<IndexEntries>,
+    {
+        let Some(records) = read_journal(journal)? else {
+            // Rebuilt index already contains all entries.
+            return Ok((rebuild()?, HashSet::new()));
+        };
+        let mut entries = match self.read_snapshot() {
+            Some(entries) => entries,
+            None => rebuild()?,
+        };
+        let released = apply_records(&mut entries, records);
+        Ok((entries, released))
+    }
+
+    fn read_snapshot(&self) -> Option<IndexEntries> {
+        let file = File::open(&self.snapshot_path).ok()?;
+        let snapshot: IndexSnapshot = bincode::deserialize_from(BufReader::new(file)).ok()?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return None;
+        }
+        Some(snapshot.entries)
+    }
+
+    fn open_journal(&self) -> crate::Result<File> {
+        if let Some(parent) = self.journal_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&self.journal_path)?)
+    }
+
+    fn append(&self, record: &IndexRecord) -> crate::Result<()> {
+        let mut journal = self.open_journal()?;
+        journal.lock_exclusive()?;
+        write_record(&mut journal, record)
+    }
+}
+
+// Append record to journal locked by caller.
+fn write_record(journal: &mut File, record: &IndexRecord) -> crate::Result<()> {
+    let payload = bincode::serialize(record)?;
+    let mut data = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+    data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    data.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    data.extend_from_slice(&payload);
+    journal.write_all(&data)?;
+    Ok(())
+}
+
+// Returns released blobs of removed and replaced entries.
+fn apply_records(entries: &mut IndexEntries, records: Vec<IndexRecord>) -> HashSet<String> {
+    let mut released = HashSet::new();
+    for record in records {
+        if let Some(entry) = record.apply(entries) {
+            released.extend(entry.blobs.into_iter().map(|(blob, _)| blob));
+        }
+    }
+    released
+}
+
+// Returns None if journal is damaged, as records following damaged one can't be located.
+fn read_journal(mut journal: &File) -> crate::Result<Option<Vec<IndexRecord>>> {
+    let mut data = Vec::new();
+    journal.rewind()?;
+    journal.read_to_end(&mut data)?;
+    let records = parse_journal(&data);
+    if records.is_none() {
+        warn!("Cache index journal is damaged, rebuilding index");
+    }
+    Ok(records)
+}
+
+fn parse_journal(mut data: &[u8]) -> Option<Vec<IndexRecord>> {
+    let mut records = Vec::new();
+    while data.len() >= RECORD_HEADER_SIZE {
+        let size = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(data[4..8].try_into().unwrap());
+        // Trailing record is incomplete if writer process was killed.
+        let Some(payload) = data.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + size) else {
+            break;
+        };
+        if crc32fast::hash(payload) != crc {
+            return None;
+        }
+        records.push(bincode::deserialize(payload).ok()?);
+        data = &data[RECORD_HEADER_SIZE + size..];
+    }
+    Some(records)
+}
+
+#[must_use]
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+fn now() -> u64 {
+    unix_time(SystemTime::now())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::fs;
+    use std::fs::OpenOptions;
+
+    use crate::cache::EntryMeta;
+    use crate::io::cacheindex::{CacheIndex, IndexEntries, JOURNAL_NAME};
+
+    #[test]
+    fn test_journal_compaction() {
+        let temp = tempfile::tempdir().unwrap();
+        let index = CacheIndex::new(temp.path());
+        let meta = EntryMeta {
+            toolchain: Some("clang".to_string()),
+            source: Some("main.cpp".to_string()),
+        };
+        index
+            .record_put("aa", 10, &meta, &[("blob".to_string(), 5)])
+            .unwrap();
+        index
+            .record_put("bb", 20, &EntryMeta::default(), &[])
+            .unwrap();
+        index.record_access("aa").unwrap();
+        index.record_remove("bb").unwrap();
+
+        let entries = index.load(|| Ok(IndexEntries::new())).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries["aa"].size, 10);
+        assert_eq!(entries["aa"].toolchain.as_deref(), Some("clang"));
+        assert_eq!(entries["aa"].source.as_deref(), Some("main.cpp"));
+        assert_eq!(entries["aa"].blobs, vec![("blob".to_string(), 5)]);
+
+        // Blobs of removed entry are released.
+        index.record_remove("aa").unwrap();
+        index
+            .update(
+                || Ok(IndexEntries::new()),
+                |entries, released| {
+                    assert!(entries.is_empty());
+                    assert_eq!(released, HashSet::from(["blob".to_string()]));
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(index.journal_size(), 0);
+        assert!(index.load(|| unreachable!()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_journal_damaged() {
+        let temp = tempfile::tempdir().unwrap();
+        let index = CacheIndex::new(temp.path());
+        index
+            .update(|| Ok(IndexEntries::new()), |_, _| Ok(()))
+            .unwrap();
+        index
+            .record_put("aa", 10, &EntryMeta::default(), &[])
+            .unwrap();
+        let size = index.journal_size();
+
+        // Incomplete trailing record is ignored.
+        index
+            .record_put("bb", 20, &EntryMeta::default(), &[])
+            .unwrap();
+        let journal = temp.path().join(JOURNAL_NAME);
+        let file = OpenOptions::new().write(true).open(&journal).unwrap();
+        file.set_len(index.journal_size() - 1).unwrap();
+        let entries = index.load(|| unreachable!()).unwrap();
+        assert_eq!(entries.keys().collect::<Vec<_>>(), vec!["aa"]);
+
+        // Records after torn one can't be trusted, so index is rebuilt.
+        index
+            .record_put("cc", 30, &EntryMeta::default(), &[])
+            .unwrap();
+        let rebuilt = index
+            .load(|| {
+                Ok(IndexEntries::from([(
+                    "rebuilt".to_string(),
+                    entries["aa"].clone(),
+                )]))
+            })
+            .unwrap();
+        assert!(rebuilt.contains_key("rebuilt"));
+
+        // Damaged record is detected by checksum.
+        file.set_len(size).unwrap();
+        let mut data = fs::read(&journal).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xFF;
+        fs::write(&journal, data).unwrap();
+        assert!(index.load(|| Ok(IndexEntries::new())).unwrap().is_empty());
+    }
+}
@@ -1,30 +1,72 @@
 use std::ffi::OsString;
 use std::fs;
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 
-use std::time::SystemTime;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
 
+use crate::cache::{CacheBackend, CacheEntryWriter, EntryMeta};
 use crate::compiler::OutputInfo;
-use crate::config::Config;
-use crate::io::binary::{read_exact, read_u64, read_usize, write_u64, write_usize};
+use crate::config::{is_valid_namespace, CacheCompression, Config};
+use crate::io::binary::{
+    read_exact, read_u32, read_u64, read_usize, write_u32, write_u64, write_usize,
+};
+use crate::io::cacheindex::{unix_time, CacheIndex, IndexEntries, IndexEntry};
 use crate::io::counter::Counter;
 use crate::io::statistic::Statistic;
+use fs2::FileExt;
+use log::warn;
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
 use thiserror::Error;
 
-const HEADER: &[u8] = b"OBCF\x00\x03";
+// Entry header is followed by compression codec, references to blobs and compressed payload.
+// Every packed file is either stored inline or references a blob, and is followed by CRC32 of its content.
+const HEADER: &[u8] = b"OBCF\x00\x04";
+// Entries of previous versions are lz4 compressed as a whole, including header.
+// They contain only successful compilation results, without blobs and checksums.
+const LEGACY_HEADER: &[u8] = b"OBCF\x00\x03";
+const LZ4_MAGIC: &[u8] = b"\x04\x22\x4D\x18";
 const FOOTER: &[u8] = b"END\x00";
+// Kept for compatibility with existing caches, entry content may use any compression.
 const SUFFIX: &str = ".lz4";
+const LOCK_EXTENSION: &str = "lock";
+const TEMP_PREFIX: &str = "~tmp~";
+// Journal size that triggers index compaction at the end of build without explicit cleanup.
+const JOURNAL_COMPACT_SIZE: u64 = 16 * 1024 * 1024;
+const BLOB_DIR: &str = "blobs";
+// Caches of namespaces are stored in subdirectories of shared cache directory.
+const NAMESPACE_DIR: &str = "namespaces";
+const BLOB_HEADER: &[u8] = b"OBBL\x00\x01";
+// Uncompressed blobs are stored as is, so they can be restored by reflink.
+const RAW_SUFFIX: &str = ".raw";
+// Smaller files are packed into entry itself.
+const BLOB_MIN_SIZE: u64 = 64 * 1024;
+// Unreferenced blobs younger than this may belong to entries being written.
+const ORPHAN_BLOB_AGE: Duration = Duration::from_secs(60 * 60);
+// Kinds of packed files.
+const PACKED_INLINE: u8 = 0;
+const PACKED_BLOB: u8 = 1;
 
 #[derive(Error, Debug)]
 pub enum CacheError {
     #[error("invalid cache file header: {0}")]
-    InvalidHeader(PathBuf),
+    InvalidHeader(String),
     #[error("invalid cache file footer: {0}")]
-    InvalidFooter(PathBuf),
+    InvalidFooter(String),
     #[error("unexpected count of packed cached files: {0}")]
-    PackedFilesMismatch(PathBuf),
+    PackedFilesMismatch(String),
+    #[error("cache file checksum mismatch: {0}")]
+    ChecksumMismatch(String),
+    #[error("invalid packed file: {0}")]
+    InvalidPackedFile(String),
+    #[error("cache blob not found: {0}")]
+    BlobNotFound(String),
+    #[error("corrupted cache stream: {0}")]
+    CorruptStream(String),
     #[error("mutex error: {0}")]
     MutexError(String),
 }
@@ -32,7 +74,75 @@ pub enum CacheError {
 pub struct FileCache {
     cache_dir: PathBuf,
     cache_limit: u64,
-    cache_compression_level: u32,
+    // Record entry access in index (false - cache directory is not modified by reads).
+    record_access: bool,
+    index: Arc<CacheIndex>,
+    blobs: Arc<BlobStore>,
+}
+
+// Storage of large output files shared between cache entries, addressed by content hash.
+//
+// Blob consists of header, compression codec and compressed file content.
+// Uncompressed blob is a plain copy of file content.
+struct BlobStore {
+    dir: PathBuf,
+}
+
+// Output file stored in blob store.
+pub struct StoredBlob {
+    pub hash: String,
+    // Size of blob file.
+    pub stored_size: u64,
+    // Size and CRC32 of original file.
+    pub size: u64,
+    pub checksum: u32,
+    // Blob was written, not reused.
+    pub created: bool,
+}
+
+// Settings for writing of new entries.
+#[derive(Clone, Debug)]
+pub struct EntryFormat {
+    pub compression: CacheCompression,
+    pub compression_level: u32,
+}
+
+enum Encoder<W: Write> {
+    None(W),
+    Lz4(lz4::Encoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+// Computes CRC32 of all data read through it.
+struct ChecksumReader<R: Read> {
+    reader: R,
+    hasher: crc32fast::Hasher,
+}
+
+// Error of storage the entry is read from (like network failure), which says nothing about entry content.
+#[derive(Debug)]
+struct SourceError(std::io::Error);
+
+// Reads entry from its storage, marking errors of the storage.
+struct EntrySource<R: Read>(R);
+
+// Reads entry content, reporting errors of decoding and unexpected end of entry as corrupted stream.
+struct EntryStream<R: Read> {
+    reader: R,
+    hash: String,
+}
+
+// Content of cache entry.
+pub struct EntryContent {
+    pub files: Vec<PackedFile>,
+    pub output: OutputInfo,
+}
+
+// Output file packed into cache entry.
+pub struct PackedFile {
+    pub size: u64,
+    // Blob storing file content (None - content is stored in entry).
+    pub blob: Option<String>,
 }
 
 struct CacheFile {
@@ -41,123 +151,947 @@ struct CacheFile {
     accessed: SystemTime,
 }
 
+// Advisory cross-process lock for writing of single cache entry.
+struct EntryLock {
+    file: Option<File>,
+    path: PathBuf,
+}
+
+struct FileEntryWriter {
+    temp: NamedTempFile,
+    path: PathBuf,
+    hash: String,
+    meta: EntryMeta,
+    index: Arc<CacheIndex>,
+    blobs: Arc<BlobStore>,
+    // Blobs referenced by entry with their stored sizes.
+    stored: Vec<(String, u64)>,
+    // Held until entry is renamed to its final path.
+    _lock: EntryLock,
+}
+
 impl FileCache {
     #[must_use]
     pub fn new(config: &Config) -> Self {
+        let cache_dir = match &config.cache_namespace {
+            Some(namespace) => config.cache.join(NAMESPACE_DIR).join(namespace),
+            None => config.cache.clone(),
+        };
         FileCache {
-            cache_dir: config.cache.clone(),
-            cache_limit: config.cache_limit_mb * 1024 * 1024,
-            cache_compression_level: config.cache_compression_level,
+            cache_limit: config.namespace_limit_mb(config.cache_namespace.as_deref()) * 1024 * 1024,
+            record_access: config.cache_mode.writes(),
+            index: Arc::new(CacheIndex::new(&cache_dir)),
+            blobs: Arc::new(BlobStore {
+                dir: cache_dir.join(BLOB_DIR),
+            }),
+            cache_dir,
         }
     }
 
-    pub fn run_cached<F: FnOnce() -> crate::Result<OutputInfo>>(
-        &self,
-        statistic: &Statistic,
-        hash: &str,
-        outputs: Vec<PathBuf>,
-        worker: F,
-    ) -> crate::Result<OutputInfo> {
-        let path = self
-            .cache_dir
+    // Namespaces having own directories in cache directory.
+    pub fn namespaces(cache: &Path) -> crate::Result<Vec<String>> {
+        let mut namespaces = Vec::new();
+        match fs::read_dir(cache.join(NAMESPACE_DIR)) {
+            Ok(dirs) => {
+                for dir in dirs {
+                    let dir = dir?;
+                    if let Some(name) = dir.file_name().to_str() {
+                        if dir.file_type()?.is_dir() && is_valid_namespace(name) {
+                            namespaces.push(name.to_string());
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        namespaces.sort();
+        Ok(namespaces)
+    }
+
+    #[must_use]
+    pub fn dir(&self) -> &Path {
+        &self.cache_dir
+    }
+
+    #[must_use]
+    pub fn limit(&self) -> u64 {
+        self.cache_limit
+    }
+
+    fn entry_path(&self, hash: &str) -> PathBuf {
+        self.cache_dir
             .join(&hash[0..2])
-            .join(hash[2..].to_string() + SUFFIX);
-        // Try to read data from cache.
-        if let Ok(output) = self.read_cache(statistic, &path, &outputs) {
-            return Ok(output);
+            .join(hash[2..].to_string() + SUFFIX)
+    }
+
+    // Open entry without updating its access time.
+    pub fn open(&self, hash: &str) -> crate::Result<Option<File>> {
+        validate_hash(hash)?;
+        match File::open(self.entry_path(hash)) {
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
         }
-        // Run task and save result to cache.
-        let output = worker()?;
-        self.write_cache(statistic, &path, outputs, &output)?;
-        Ok(output)
     }
 
-    pub fn cleanup(&self) -> crate::Result<()> {
-        let mut files = find_cache_files(&self.cache_dir, Vec::new())?;
-        files.sort_by(|a, b| b.accessed.cmp(&a.accessed));
+    // Current state of cache index.
+    pub fn entries(&self) -> crate::Result<IndexEntries> {
+        self.index.load(|| self.scan_entries())
+    }
 
-        let mut cache_size: u64 = 0;
-        for item in &files {
-            cache_size += item.size;
-            if cache_size > self.cache_limit {
-                fs::remove_file(&item.path)?;
+    // Rebuild index from cache directory content.
+    // Also removes blobs not referenced by any entry.
+    fn scan_entries(&self) -> crate::Result<IndexEntries> {
+        let mut entries = IndexEntries::new();
+        if !self.cache_dir.is_dir() {
+            return Ok(entries);
+        }
+        let namespaces = self.cache_dir.join(NAMESPACE_DIR);
+        for file in find_cache_files(&self.cache_dir, Vec::new())? {
+            // Entries of namespaces belong to their own caches.
+            if file.path.starts_with(&namespaces) {
+                continue;
+            }
+            if let Some(hash) = entry_hash(&file.path) {
+                let blobs = File::open(&file.path)
+                    .map_err(crate::Error::from)
+                    .and_then(|entry| entry_blobs(&hash, entry))
+                    .unwrap_or_default();
+                entries.insert(
+                    hash,
+                    IndexEntry {
+                        size: file.size,
+                        accessed: unix_time(file.accessed),
+                        toolchain: None,
+                        source: None,
+                        blobs,
+                    },
+                );
+            }
+        }
+        let referenced: HashSet<&str> = entries
+            .values()
+            .flat_map(|entry| entry.blobs.iter().map(|(blob, _)| blob.as_str()))
+            .collect();
+        let orphan_time = SystemTime::now() - ORPHAN_BLOB_AGE;
+        for (blob, modified) in self.blobs.scan()? {
+            if modified < orphan_time && !referenced.contains(blob.as_str()) {
+                self.blobs.remove(&blob);
             }
         }
+        Ok(entries)
+    }
+}
+
+/// Checks that hash received from user can be used as entry file name inside cache directory.
+pub fn validate_hash(hash: &str) -> crate::Result<()> {
+    if hash.len() < 3 || !hash.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err(crate::Error::Generic(format!(
+            "invalid cache entry hash: {hash}"
+        )));
+    }
+    Ok(())
+}
+
+// Restore entry hash from its file path.
+fn entry_hash(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?.strip_suffix(SUFFIX)?;
+    let prefix = path.parent()?.file_name()?.to_str()?;
+    if prefix.len() != 2 || name.starts_with(TEMP_PREFIX) {
+        return None;
+    }
+    Some(prefix.to_string() + name)
+}
+
+impl CacheBackend for FileCache {
+    fn get(&self, hash: &str) -> crate::Result<Option<Box<dyn Read>>> {
+        let Some(file) = self.open(hash)? else {
+            return Ok(None);
+        };
+        if self.record_access {
+            if let Err(e) = self.index.record_access(hash) {
+                warn!("Can't update cache index: {}", e);
+            }
+        }
+        Ok(Some(Box::new(file)))
+    }
+
+    fn get_blob(&self, hash: &str) -> crate::Result<Option<Box<dyn Read>>> {
+        self.blobs.open(hash)
+    }
+
+    fn restore_blob(&self, hash: &str, path: &Path) -> crate::Result<bool> {
+        self.blobs.restore(hash, path)
+    }
+
+    fn put(
+        &self,
+        hash: &str,
+        meta: &EntryMeta,
+    ) -> crate::Result<Option<Box<dyn CacheEntryWriter>>> {
+        self.writer(hash, meta, false)
+    }
+
+    fn replace(
+        &self,
+        hash: &str,
+        meta: &EntryMeta,
+    ) -> crate::Result<Option<Box<dyn CacheEntryWriter>>> {
+        self.writer(hash, meta, true)
+    }
+
+    fn contains(&self, hash: &str) -> bool {
+        self.entry_path(hash).is_file()
+    }
+
+    fn evict(&self, hash: &str) -> crate::Result<()> {
+        match fs::remove_file(self.entry_path(hash)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        self.index.record_remove(hash)
+    }
+
+    fn cleanup(&self) -> crate::Result<()> {
+        self.shrink(self.cache_limit, 0).map(drop)
+    }
+
+    fn cleanup_if_needed(&self) -> crate::Result<()> {
+        if self.index.journal_size() > JOURNAL_COMPACT_SIZE {
+            self.cleanup()?;
+        }
         Ok(())
     }
+}
 
-    fn read_cache(
+impl FileCache {
+    // Start writing of entry under its lock (replace - overwrite already stored entry on commit).
+    fn writer(
         &self,
-        statistic: &Statistic,
-        path: &PathBuf,
-        paths: &[PathBuf],
-    ) -> crate::Result<OutputInfo> {
-        let mut file = OpenOptions::new()
+        hash: &str,
+        meta: &EntryMeta,
+        replace: bool,
+    ) -> crate::Result<Option<Box<dyn CacheEntryWriter>>> {
+        let path = self.entry_path(hash);
+        let parent = path.parent().unwrap();
+        fs::create_dir_all(parent)?;
+        let lock = match EntryLock::try_lock(path.with_extension(LOCK_EXTENSION))? {
+            Some(lock) => lock,
+            // Entry is being written by another process.
+            None => return Ok(None),
+        };
+        // Entry was written while we were waiting for lock.
+        if !replace && path.is_file() {
+            return Ok(None);
+        }
+        Ok(Some(Box::new(FileEntryWriter {
+            temp: tempfile::Builder::new()
+                .prefix(TEMP_PREFIX)
+                .tempfile_in(parent)?,
+            path,
+            hash: hash.to_string(),
+            meta: meta.clone(),
+            index: self.index.clone(),
+            blobs: self.blobs.clone(),
+            stored: Vec::new(),
+            _lock: lock,
+        })))
+    }
+
+    // Remove least recently used entries above `max_size` and entries not accessed since `accessed_after`.
+    // Blobs are removed along with the last entry referencing them.
+    // Returns count of removed entries.
+    pub fn shrink(&self, max_size: u64, accessed_after: u64) -> crate::Result<usize> {
+        let mut removed = 0;
+        self.index.update(
+            || self.scan_entries(),
+            |entries, mut released| {
+                let mut files: Vec<(&String, &IndexEntry)> = entries.iter().collect();
+                files.sort_by(|a, b| b.1.accessed.cmp(&a.1.accessed));
+
+                let mut evicted = Vec::new();
+                let mut cache_size: u64 = 0;
+                // Shared blob is accounted to the most recently used entry referencing it.
+                let mut counted = HashSet::new();
+                for (hash, entry) in files {
+                    cache_size += entry.size;
+                    for (blob, size) in &entry.blobs {
+                        if counted.insert(blob) {
+                            cache_size += size;
+                        }
+                    }
+                    if cache_size > max_size || entry.accessed < accessed_after {
+                        match fs::remove_file(self.entry_path(hash)) {
+                            Ok(()) => evicted.push(hash.clone()),
+                            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                                evicted.push(hash.clone());
+                            }
+                            // Entry may be opened by another process.
+                            Err(e) => warn!("Can't remove cache entry {}: {}", hash, e),
+                        }
+                    }
+                }
+                removed = evicted.len();
+                for hash in evicted {
+                    if let Some(entry) = entries.remove(&hash) {
+                        released.extend(entry.blobs.into_iter().map(|(blob, _)| blob));
+                    }
+                }
+                let referenced: HashSet<&String> = entries
+                    .values()
+                    .flat_map(|entry| entry.blobs.iter().map(|(blob, _)| blob))
+                    .collect();
+                for blob in &released {
+                    if !referenced.contains(blob) {
+                        self.blobs.remove(blob);
+                    }
+                }
+                Ok(())
+            },
+        )?;
+        Ok(removed)
+    }
+}
+
+impl EntryLock {
+    fn try_lock(path: PathBuf) -> crate::Result<Option<Self>> {
+        let file = OpenOptions::new()
             .read(true)
             .write(true)
-            .open(PathBuf::from(path))?;
-        file.write_all(&[4])?;
-        file.rewind()?;
-        let mut stream = lz4::Decoder::new(Counter::reader(file))?;
-        if read_exact(&mut stream, HEADER.len())? != HEADER {
-            return Err(CacheError::InvalidHeader(path.clone()).into());
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        match file.try_lock_exclusive() {
+            Ok(()) => Ok(Some(EntryLock {
+                file: Some(file),
+                path,
+            })),
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => Ok(None),
+            Err(e) => Err(e.into()),
         }
-        if read_usize(&mut stream)? != paths.len() {
-            return Err(CacheError::PackedFilesMismatch(path.clone()).into());
+    }
+}
+
+impl Drop for EntryLock {
+    fn drop(&mut self) {
+        // Lock is released on close.
+        drop(self.file.take());
+        drop(fs::remove_file(&self.path));
+    }
+}
+
+impl Write for FileEntryWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.temp.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.temp.flush()
+    }
+}
+
+impl CacheEntryWriter for FileEntryWriter {
+    fn put_blob(&mut self, path: &Path, format: &EntryFormat) -> crate::Result<Option<StoredBlob>> {
+        let blob = self.blobs.put(path, format)?;
+        self.stored.push((blob.hash.clone(), blob.stored_size));
+        Ok(Some(blob))
+    }
+
+    fn commit(self: Box<Self>) -> crate::Result<()> {
+        let FileEntryWriter {
+            temp,
+            path,
+            hash,
+            meta,
+            index,
+            blobs,
+            stored,
+            _lock,
+        } = *self;
+        let size = temp.as_file().metadata()?.len();
+        let mut persisted = false;
+        let result = index.commit_put(&hash, size, &meta, &stored, || {
+            // Reused blob may be removed by cleanup of entries referencing it before.
+            if let Some((blob, _)) = stored
+                .iter()
+                .find(|(blob, _)| blobs.existing(blob).is_none())
+            {
+                return Err(CacheError::BlobNotFound(blob.clone()).into());
+            }
+            // Entry becomes visible to readers atomically. Temporary file is removed on drop otherwise,
+            // so interrupted writes never leave truncated entries.
+            temp.persist(&path).map_err(|e| crate::Error::IO(e.error))?;
+            persisted = true;
+            Ok(())
+        });
+        match result {
+            Err(e) if persisted => warn!("Can't update cache index: {}", e),
+            result => result?,
         }
+        Ok(())
+    }
+}
+
+/// Unpacks cache entry into output files.
+pub fn read_cache(
+    statistic: &Statistic,
+    hash: &str,
+    entry: impl Read,
+    paths: &[PathBuf],
+    blobs: &dyn CacheBackend,
+) -> crate::Result<OutputInfo> {
+    let mut counter = Counter::reader(entry);
+    let (mut stream, legacy) = open_payload(&mut counter, hash)?;
+    let status = read_status(&mut stream, legacy)?;
+    // Failed compilation doesn't produce output files.
+    let paths = if status == Some(0) {
+        paths
+    } else {
         for path in paths {
-            assert!(path.is_absolute());
-            let mut temp_name = OsString::from("~tmp~");
-            temp_name.push(path.file_name().unwrap());
-            let temp = path.with_file_name(temp_name);
             drop(fs::remove_file(path));
-            match read_cached_file(&mut stream, &temp).and_then(|_| Ok(fs::rename(&temp, path)?)) {
-                Ok(_) => {}
-                Err(e) => {
-                    drop(fs::remove_file(&temp));
-                    return Err(e);
+        }
+        &[]
+    };
+    if read_usize(&mut stream)? != paths.len() {
+        return Err(CacheError::PackedFilesMismatch(hash.to_string()).into());
+    }
+    let mut blob_size = 0;
+    for path in paths {
+        assert!(path.is_absolute());
+        let mut temp_name = OsString::from(TEMP_PREFIX);
+        temp_name.push(path.file_name().unwrap());
+        let temp = path.with_file_name(temp_name);
+        drop(fs::remove_file(path));
+        match read_cached_file(&mut stream, &temp, legacy, blobs, hash)
+            .and_then(|file| Ok((file, fs::rename(&temp, path)?)))
+        {
+            Ok((file, ())) => {
+                if file.blob.is_some() {
+                    blob_size += file.size;
                 }
-            };
+            }
+            Err(e) => {
+                drop(fs::remove_file(&temp));
+                return Err(e);
+            }
+        };
+    }
+    let output = read_output(&mut stream, status)?;
+    read_footer(&mut stream, hash)?;
+    drop(stream);
+    statistic.add_hit(counter.len() + blob_size as usize);
+    Ok(output)
+}
+
+/// Reads whole cache entry and verifies its checksums without unpacking files.
+pub fn inspect_cache(
+    hash: &str,
+    mut entry: impl Read,
+    blobs: &dyn CacheBackend,
+) -> crate::Result<EntryContent> {
+    let (mut stream, legacy) = open_payload(&mut entry, hash)?;
+    let status = read_status(&mut stream, legacy)?;
+    let count = read_usize(&mut stream)?;
+    let mut files = Vec::new();
+    for _ in 0..count {
+        let file = read_packed_header(&mut stream, legacy, hash)?;
+        copy_packed_file(
+            &mut stream,
+            &file,
+            legacy,
+            blobs,
+            &mut std::io::sink(),
+            hash,
+        )?;
+        files.push(file);
+    }
+    let output = read_output(&mut stream, status)?;
+    read_footer(&mut stream, hash)?;
+    Ok(EntryContent { files, output })
+}
+
+/// Copies cache entry replacing blob references by blob content,
+/// so the copy doesn't depend on blob store.
+pub fn repack_cache(
+    hash: &str,
+    mut entry: impl Read,
+    blobs: &dyn CacheBackend,
+    mut target: Box<dyn CacheEntryWriter>,
+    format: &EntryFormat,
+) -> crate::Result<()> {
+    let (mut stream, legacy) = open_payload(&mut entry, hash)?;
+    let status = read_status(&mut stream, legacy)?;
+    let count = read_usize(&mut stream)?;
+    target.write_all(HEADER)?;
+    target.write_all(&[format.compression.codec()])?;
+    write_usize(&mut target, 0)?;
+    let mut writer = Encoder::new(&mut target, format)?;
+    write_status(&mut writer, status)?;
+    write_usize(&mut writer, count)?;
+    for _ in 0..count {
+        let file = read_packed_header(&mut stream, legacy, hash)?;
+        writer.write_all(&[PACKED_INLINE])?;
+        write_u64(&mut writer, file.size)?;
+        let checksum = copy_packed_file(&mut stream, &file, legacy, blobs, &mut writer, hash)?;
+        write_u32(&mut writer, checksum)?;
+    }
+    let output = read_output(&mut stream, status)?;
+    read_footer(&mut stream, hash)?;
+    write_output(&mut writer, &output)?;
+    writer.write_all(FOOTER)?;
+    writer.finish()?;
+    target.commit()
+}
+
+/// Lists blobs referenced by cache entry with their stored sizes, without unpacking the entry.
+pub fn entry_blobs(hash: &str, mut entry: impl Read) -> crate::Result<Vec<(String, u64)>> {
+    if read_exact(&mut entry, HEADER.len())? == HEADER {
+        read_exact(&mut entry, 1)?;
+        read_blob_refs(&mut entry, hash)
+    } else {
+        // Entries of previous versions contain all files.
+        Ok(Vec::new())
+    }
+}
+
+fn read_footer(stream: &mut impl Read, hash: &str) -> crate::Result<()> {
+    if read_exact(stream, FOOTER.len())? != FOOTER {
+        return Err(CacheError::InvalidFooter(hash.to_string()).into());
+    }
+    let mut eof = [0];
+    if stream.read(&mut eof)? != 0 {
+        return Err(CacheError::InvalidFooter(hash.to_string()).into());
+    }
+    Ok(())
+}
+
+// Check entry header and create decoder for the rest of entry.
+// Also returns whether entry was written in legacy format.
+fn open_payload<'a, R: Read>(
+    entry: &'a mut R,
+    hash: &str,
+) -> crate::Result<(Box<dyn Read + 'a>, bool)> {
+    let mut entry = EntryStream::new(EntrySource(entry), hash);
+    let header = read_exact(&mut entry, HEADER.len())?;
+    if header == HEADER {
+        let codec = read_exact(&mut entry, 1)?[0];
+        read_blob_refs(&mut entry, hash)?;
+        return Ok((decoder(codec, entry, hash)?, false));
+    }
+    if header.starts_with(LZ4_MAGIC) {
+        // Entry written by previous versions: header is compressed too.
+        let stream =
+            lz4::Decoder::new(Cursor::new(header).chain(entry)).map_err(|e| corrupt(e, hash))?;
+        let mut stream = EntryStream::new(stream, hash);
+        if read_exact(&mut stream, LEGACY_HEADER.len())? == LEGACY_HEADER {
+            return Ok((Box::new(stream), true));
+        }
+    }
+    Err(CacheError::InvalidHeader(hash.to_string()).into())
+}
+
+fn decoder<'a, R: Read + 'a>(
+    codec: u8,
+    reader: EntryStream<R>,
+    hash: &str,
+) -> crate::Result<Box<dyn Read + 'a>> {
+    Ok(match CacheCompression::from_codec(codec) {
+        Some(CacheCompression::None) => Box::new(reader),
+        Some(CacheCompression::Lz4) => Box::new(EntryStream::new(
+            lz4::Decoder::new(reader).map_err(|e| corrupt(e, hash))?,
+            hash,
+        )),
+        Some(CacheCompression::Zstd) => Box::new(EntryStream::new(
+            zstd::Decoder::new(reader).map_err(|e| corrupt(e, hash))?,
+            hash,
+        )),
+        None => return Err(CacheError::InvalidHeader(hash.to_string()).into()),
+    })
+}
+
+// Blob references are stored uncompressed after entry header.
+fn read_blob_refs(stream: &mut impl Read, hash: &str) -> crate::Result<Vec<(String, u64)>> {
+    let count = read_usize(stream)?;
+    let mut blobs = Vec::new();
+    for _ in 0..count {
+        let blob = read_blob_hash(stream, hash)?;
+        blobs.push((blob, read_u64(stream)?));
+    }
+    Ok(blobs)
+}
+
+fn read_blob_hash(stream: &mut impl Read, hash: &str) -> crate::Result<String> {
+    String::from_utf8(read_blob(stream)?)
+        .map_err(|_| CacheError::InvalidPackedFile(hash.to_string()).into())
+}
+
+/// Packs output files and compiler output into cache entry.
+pub fn write_cache(
+    statistic: &Statistic,
+    mut entry: Box<dyn CacheEntryWriter>,
+    paths: Vec<PathBuf>,
+    output: &OutputInfo,
+    format: &EntryFormat,
+) -> crate::Result<()> {
+    // Only status and compiler output are stored for failed compilation.
+    let paths = if output.success() { paths } else { Vec::new() };
+    // Large files are shared between entries if backend supports blobs.
+    let mut blobs = Vec::with_capacity(paths.len());
+    for path in &paths {
+        assert!(path.is_absolute());
+        blobs.push(
+            if fs::metadata(path).is_ok_and(|stat| stat.len() >= BLOB_MIN_SIZE) {
+                entry.put_blob(path, format)?
+            } else {
+                None
+            },
+        );
+    }
+    let mut writer = Counter::writer(&mut entry);
+    writer.write_all(HEADER)?;
+    writer.write_all(&[format.compression.codec()])?;
+    write_usize(&mut writer, blobs.iter().flatten().count())?;
+    for blob in blobs.iter().flatten() {
+        write_blob(&mut writer, blob.hash.as_bytes())?;
+        write_u64(&mut writer, blob.stored_size)?;
+    }
+    let mut stream = Encoder::new(&mut writer, format)?;
+    write_status(&mut stream, output.status)?;
+    write_usize(&mut stream, paths.len())?;
+    for (path, blob) in paths.into_iter().zip(&blobs) {
+        match blob {
+            Some(blob) => {
+                stream.write_all(&[PACKED_BLOB])?;
+                write_u64(&mut stream, blob.size)?;
+                write_blob(&mut stream, blob.hash.as_bytes())?;
+                write_u32(&mut stream, blob.checksum)?;
+            }
+            None => {
+                stream.write_all(&[PACKED_INLINE])?;
+                write_cached_file(&mut stream, path)?;
+            }
+        }
+    }
+    write_output(&mut stream, output)?;
+    stream.write_all(FOOTER)?;
+    stream.finish()?;
+    let size = writer.len()
+        + blobs
+            .iter()
+            .flatten()
+            .filter(|blob| blob.created)
+            .map(|blob| blob.stored_size as usize)
+            .sum::<usize>();
+    entry.commit()?;
+    // Failed compilation is counted as error, not as miss.
+    if output.success() {
+        statistic.add_miss(size);
+    }
+    Ok(())
+}
+
+impl BlobStore {
+    fn path(&self, hash: &str) -> PathBuf {
+        self.dir.join(&hash[0..2]).join(&hash[2..])
+    }
+
+    fn raw_path(&self, hash: &str) -> PathBuf {
+        self.dir
+            .join(&hash[0..2])
+            .join(hash[2..].to_string() + RAW_SUFFIX)
+    }
+
+    // Path of stored blob in any format.
+    fn existing(&self, hash: &str) -> Option<PathBuf> {
+        [self.raw_path(hash), self.path(hash)]
+            .into_iter()
+            .find(|path| path.is_file())
+    }
+
+    // Store file content unless the same content is already stored.
+    fn put(&self, path: &Path, format: &EntryFormat) -> crate::Result<StoredBlob> {
+        let mut file = File::open(path).map_err(|e| crate::Error::FileOpen {
+            path: path.to_path_buf(),
+            error: Box::new(e.into()),
+        })?;
+        fs::create_dir_all(&self.dir)?;
+        let mut temp = tempfile::Builder::new()
+            .prefix(TEMP_PREFIX)
+            .tempfile_in(&self.dir)?;
+        let raw = format.compression == CacheCompression::None;
+        if !raw {
+            temp.write_all(BLOB_HEADER)?;
+            temp.write_all(&[format.compression.codec()])?;
+        }
+        let mut encoder = Encoder::new(temp.as_file_mut(), format)?;
+        let mut checksum = crc32fast::Hasher::new();
+        let mut digest = Sha256::new();
+        let mut size = 0;
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            checksum.update(&buffer[..read]);
+            digest.update(&buffer[..read]);
+            encoder.write_all(&buffer[..read])?;
+            size += read as u64;
         }
-        let output = read_output(&mut stream)?;
-        if read_exact(&mut stream, FOOTER.len())? != FOOTER {
-            return Err(CacheError::InvalidFooter(path.clone()).into());
+        encoder.finish()?;
+
+        let hash = hex::encode(digest.finalize());
+        let (blob_path, created) = match self.existing(&hash) {
+            Some(existing) => {
+                // Keep reused blob away from removal of recently orphaned ones.
+                File::options()
+                    .write(true)
+                    .open(&existing)?
+                    .set_modified(SystemTime::now())?;
+                (existing, false)
+            }
+            None => {
+                let blob_path = if raw {
+                    self.raw_path(&hash)
+                } else {
+                    self.path(&hash)
+                };
+                fs::create_dir_all(blob_path.parent().unwrap())?;
+                temp.persist(&blob_path)
+                    .map_err(|e| crate::Error::IO(e.error))?;
+                (blob_path, true)
+            }
+        };
+        Ok(StoredBlob {
+            stored_size: fs::metadata(&blob_path)?.len(),
+            hash,
+            size,
+            checksum: checksum.finalize(),
+            created,
+        })
+    }
+
+    // Open decoded blob content (None - blob not found).
+    fn open(&self, hash: &str) -> crate::Result<Option<Box<dyn Read>>> {
+        if hash.len() < 3 {
+            return Err(CacheError::InvalidPackedFile(hash.to_string()).into());
         }
-        let mut eof = [0];
-        if stream.read(&mut eof)? != 0 {
-            return Err(CacheError::InvalidFooter(path.clone()).into());
+        match File::open(self.raw_path(hash)) {
+            Ok(file) => return Ok(Some(Box::new(file))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
         }
-        statistic.add_hit(stream.finish().0.len());
-        Ok(output)
+        let mut file = match File::open(self.path(hash)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if read_exact(&mut file, BLOB_HEADER.len())? != BLOB_HEADER {
+            return Err(CacheError::InvalidHeader(hash.to_string()).into());
+        }
+        let codec = read_exact(&mut file, 1)?[0];
+        Ok(Some(decoder(
+            codec,
+            EntryStream::new(EntrySource(file), hash),
+            hash,
+        )?))
     }
 
-    fn write_cache(
-        &self,
-        statistic: &Statistic,
-        path: &Path,
-        paths: Vec<PathBuf>,
-        output: &OutputInfo,
-    ) -> crate::Result<()> {
-        if !output.success() {
-            return Ok(());
-        }
-        if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent)?;
-        }
-        let mut stream = lz4::EncoderBuilder::new()
-            .level(self.cache_compression_level)
-            .build(Counter::writer(File::create(path)?))?;
-        stream.write_all(HEADER)?;
-        write_usize(&mut stream, paths.len())?;
-        for path in paths {
-            assert!(path.is_absolute());
-            write_cached_file(&mut stream, path)?;
+    // Create copy-on-write clone of uncompressed blob.
+    // Returns false if blob should be copied instead.
+    //
+    // Hardlinks are not used: build tools may rewrite outputs in place, corrupting shared blob.
+    fn restore(&self, hash: &str, path: &Path) -> crate::Result<bool> {
+        if hash.len() < 3 {
+            return Ok(false);
+        }
+        let blob_path = self.raw_path(hash);
+        if !blob_path.is_file() {
+            return Ok(false);
+        }
+        drop(fs::remove_file(path));
+        Ok(reflink::reflink(&blob_path, path).is_ok())
+    }
+
+    fn remove(&self, hash: &str) {
+        for path in [self.raw_path(hash), self.path(hash)] {
+            match fs::remove_file(path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => warn!("Can't remove cache blob {}: {}", hash, e),
+            }
+        }
+    }
+
+    // List stored blobs with their modification time.
+    fn scan(&self) -> crate::Result<Vec<(String, SystemTime)>> {
+        let mut blobs = Vec::new();
+        if !self.dir.is_dir() {
+            return Ok(blobs);
+        }
+        for file in find_cache_files(&self.dir, Vec::new())? {
+            let name = file.path.file_name().and_then(|name| name.to_str());
+            let prefix = file
+                .path
+                .parent()
+                .and_then(|parent| parent.file_name())
+                .and_then(|prefix| prefix.to_str());
+            if let (Some(name), Some(prefix)) = (name, prefix) {
+                if prefix.len() == 2 && !name.starts_with(TEMP_PREFIX) {
+                    let name = name.strip_suffix(RAW_SUFFIX).unwrap_or(name);
+                    blobs.push((prefix.to_string() + name, file.accessed));
+                }
+            }
+        }
+        Ok(blobs)
+    }
+}
+
+impl EntryFormat {
+    #[must_use]
+    pub fn new(config: &Config) -> Self {
+        EntryFormat {
+            compression: config.cache_compression,
+            compression_level: config.cache_compression_level,
+        }
+    }
+}
+
+impl CacheCompression {
+    fn codec(self) -> u8 {
+        match self {
+            CacheCompression::None => 0,
+            CacheCompression::Lz4 => 1,
+            CacheCompression::Zstd => 2,
         }
-        write_output(&mut stream, output)?;
-        stream.write_all(FOOTER)?;
-        let (writer, result) = stream.finish();
-        statistic.add_miss(writer.len());
-        Ok(result?)
+    }
+
+    fn from_codec(codec: u8) -> Option<Self> {
+        match codec {
+            0 => Some(CacheCompression::None),
+            1 => Some(CacheCompression::Lz4),
+            2 => Some(CacheCompression::Zstd),
+            _ => None,
+        }
+    }
+}
+
+impl<W: Write> Encoder<W> {
+    fn new(writer: W, format: &EntryFormat) -> crate::Result<Self> {
+        Ok(match format.compression {
+            CacheCompression::None => Encoder::None(writer),
+            CacheCompression::Lz4 => Encoder::Lz4(
+                lz4::EncoderBuilder::new()
+                    .level(format.compression_level)
+                    .build(writer)?,
+            ),
+            CacheCompression::Zstd => Encoder::Zstd(zstd::Encoder::new(
+                writer,
+                i32::try_from(format.compression_level).unwrap_or(i32::MAX),
+            )?),
+        })
+    }
+
+    fn finish(self) -> crate::Result<W> {
+        Ok(match self {
+            Encoder::None(writer) => writer,
+            Encoder::Lz4(encoder) => {
+                let (writer, result) = encoder.finish();
+                result?;
+                writer
+            }
+            Encoder::Zstd(encoder) => encoder.finish()?,
+        })
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Encoder::None(writer) => writer.write(buf),
+            Encoder::Lz4(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Encoder::None(writer) => writer.flush(),
+            Encoder::Lz4(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+impl<R: Read> ChecksumReader<R> {
+    fn new(reader: R) -> Self {
+        ChecksumReader {
+            reader,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+
+    fn checksum(self) -> u32 {
+        self.hasher.finalize()
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.reader.read(buf)?;
+        self.hasher.update(&buf[..size]);
+        Ok(size)
+    }
+}
+
+impl std::fmt::Display for SourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for SourceError {}
+
+impl<R: Read> Read for EntrySource<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0
+            .read(buf)
+            .map_err(|e| std::io::Error::new(e.kind(), SourceError(e)))
+    }
+}
+
+impl<R: Read> EntryStream<R> {
+    fn new(reader: R, hash: &str) -> Self {
+        EntryStream {
+            reader,
+            hash: hash.to_string(),
+        }
+    }
+}
+
+impl<R: Read> Read for EntryStream<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reader.read(buf).map_err(|e| corrupt(e, &self.hash))
+    }
+
+    // Unexpected end of entry is reported by `read_exact` itself.
+    fn read_exact(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        self.reader
+            .read_exact(buf)
+            .map_err(|e| corrupt(e, &self.hash))
+    }
+}
+
+// Mark error of reading entry content as corrupted stream, unless it is an error of storage.
+fn corrupt(error: std::io::Error, hash: &str) -> std::io::Error {
+    match error.get_ref() {
+        Some(inner) if inner.is::<SourceError>() || inner.is::<CacheError>() => error,
+        _ => std::io::Error::new(
+            error.kind(),
+            CacheError::CorruptStream(format!("{hash}: {error}")),
+        ),
     }
 }
 
@@ -189,22 +1123,114 @@ fn write_cached_file<W: Write>(stream: &mut W, path: PathBuf) -> crate::Result<(
     let total_size = file.seek(SeekFrom::End(0))?;
     file.rewind()?;
     write_u64(stream, total_size)?;
-    let written = std::io::copy(&mut file, stream)?;
+    let mut reader = ChecksumReader::new(file);
+    let written = std::io::copy(&mut reader, stream)?;
     if written != total_size {
         return Err(crate::Error::Generic("Expected end of stream".to_string()));
     }
+    write_u32(stream, reader.checksum())?;
     Ok(())
 }
 
-fn read_cached_file(stream: &mut impl Read, path: &Path) -> crate::Result<()> {
-    let size = read_u64(stream)?;
+fn read_cached_file(
+    stream: &mut impl Read,
+    path: &Path,
+    legacy: bool,
+    blobs: &dyn CacheBackend,
+    hash: &str,
+) -> crate::Result<PackedFile> {
+    let packed = read_packed_header(stream, legacy, hash)?;
+    if let Some(blob) = &packed.blob {
+        if blobs.restore_blob(blob, path)? {
+            if fs::metadata(path)?.len() != packed.size {
+                return Err(CacheError::ChecksumMismatch(hash.to_string()).into());
+            }
+            let checksum = read_u32(stream)?;
+            if !verify_restored_blob(blob, path, checksum)? {
+                drop(fs::remove_file(path));
+                return Err(CacheError::ChecksumMismatch(hash.to_string()).into());
+            }
+            return Ok(packed);
+        }
+    }
     let mut file = File::create(path)?;
-    file.set_len(size)?;
-    let written = std::io::copy(&mut stream.take(size), &mut file)?;
+    file.set_len(packed.size)?;
+    copy_packed_file(stream, &packed, legacy, blobs, &mut file, hash)?;
+    Ok(packed)
+}
+
+// Check checksum of cloned blob content.
+// Reading whole clone defeats the purpose of cloning, so every blob is verified once per process.
+fn verify_restored_blob(blob: &str, path: &Path, checksum: u32) -> crate::Result<bool> {
+    static VERIFIED: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+    let verified = VERIFIED.get_or_init(Default::default);
+    if verified.lock().unwrap().contains(blob) {
+        return Ok(true);
+    }
+    let mut reader = ChecksumReader::new(File::open(path)?);
+    std::io::copy(&mut reader, &mut std::io::sink())?;
+    if reader.checksum() != checksum {
+        return Ok(false);
+    }
+    verified.lock().unwrap().insert(blob.to_string());
+    Ok(true)
+}
+
+fn read_packed_header(
+    stream: &mut impl Read,
+    legacy: bool,
+    hash: &str,
+) -> crate::Result<PackedFile> {
+    let kind = if legacy {
+        PACKED_INLINE
+    } else {
+        read_exact(stream, 1)?[0]
+    };
+    let size = read_u64(stream)?;
+    let blob = match kind {
+        PACKED_INLINE => None,
+        PACKED_BLOB => Some(read_blob_hash(stream, hash)?),
+        _ => return Err(CacheError::InvalidPackedFile(hash.to_string()).into()),
+    };
+    Ok(PackedFile { size, blob })
+}
+
+// Copy packed file content verifying its checksum, returns the checksum.
+fn copy_packed_file(
+    stream: &mut impl Read,
+    file: &PackedFile,
+    legacy: bool,
+    blobs: &dyn CacheBackend,
+    writer: &mut impl Write,
+    hash: &str,
+) -> crate::Result<u32> {
+    let actual = match &file.blob {
+        None => copy_with_checksum(stream, file.size, writer, hash)?,
+        Some(blob) => {
+            let mut reader = blobs
+                .get_blob(blob)?
+                .ok_or_else(|| CacheError::BlobNotFound(blob.clone()))?;
+            copy_with_checksum(&mut reader, file.size, writer, hash)?
+        }
+    };
+    if !legacy && read_u32(stream)? != actual {
+        return Err(CacheError::ChecksumMismatch(hash.to_string()).into());
+    }
+    Ok(actual)
+}
+
+fn copy_with_checksum(
+    stream: &mut impl Read,
+    size: u64,
+    writer: &mut impl Write,
+    hash: &str,
+) -> crate::Result<u32> {
+    let mut reader = ChecksumReader::new(stream.by_ref().take(size));
+    let written = std::io::copy(&mut reader, writer)?;
     if written != size {
-        return Err(crate::Error::Generic("Expected end of stream".to_string()));
+        return Err(CacheError::CorruptStream(format!("{hash}: unexpected end of stream")).into());
     }
-    Ok(())
+    Ok(reader.checksum())
 }
 
 fn write_blob(stream: &mut impl Write, blob: &[u8]) -> crate::Result<()> {
@@ -224,12 +1250,361 @@ fn write_output(stream: &mut impl Write, output: &OutputInfo) -> crate::Result<(
     Ok(())
 }
 
-fn read_output(stream: &mut impl Read) -> crate::Result<OutputInfo> {
+fn read_output(stream: &mut impl Read, status: Option<i32>) -> crate::Result<OutputInfo> {
     let stdout = read_blob(stream)?;
     let stderr = read_blob(stream)?;
     Ok(OutputInfo {
-        status: Some(0),
+        status,
         stdout,
         stderr,
     })
 }
+
+fn write_status(stream: &mut impl Write, status: Option<i32>) -> crate::Result<()> {
+    match status {
+        Some(code) => {
+            stream.write_all(&[1])?;
+            write_u32(stream, code as u32)?;
+        }
+        None => stream.write_all(&[0])?,
+    }
+    Ok(())
+}
+
+// Entries of previous versions contain only successful results.
+fn read_status(stream: &mut impl Read, legacy: bool) -> crate::Result<Option<i32>> {
+    if legacy {
+        return Ok(Some(0));
+    }
+    Ok(match read_exact(stream, 1)?[0] {
+        0 => None,
+        _ => Some(read_u32(stream)? as i32),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::io::Write;
+
+    use crate::cache::{CacheBackend, EntryMeta};
+    use crate::compiler::OutputInfo;
+    use crate::config::{CacheCompression, Config};
+    use crate::io::binary::write_usize;
+    use crate::io::filecache::{
+        entry_blobs, inspect_cache, read_cache, repack_cache, verify_restored_blob, write_cache,
+        write_output, EntryFormat, FileCache, BLOB_MIN_SIZE, FOOTER, LEGACY_HEADER,
+    };
+    use crate::io::statistic::Statistic;
+
+    fn test_output() -> OutputInfo {
+        OutputInfo {
+            status: Some(0),
+            stdout: b"stdout".to_vec(),
+            stderr: b"stderr".to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_open_invalid_hash() {
+        let temp = tempfile::tempdir().unwrap();
+        let cache = FileCache::new(&Config {
+            cache: temp.path().to_path_buf(),
+            ..Config::default()
+        });
+        assert!(cache.open("a").is_err());
+        assert!(cache.open("../x").is_err());
+        assert!(cache.open("abc").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_put_locked() {
+        let temp = tempfile::tempdir().unwrap();
+        let cache = FileCache::new(&Config {
+            cache: temp.path().to_path_buf(),
+            ..Config::default()
+        });
+        let hash = "0123456789abcdef";
+
+        // Concurrent writer skips entry.
+        let mut writer = cache.put(hash, &EntryMeta::default()).unwrap().unwrap();
+        assert!(cache.put(hash, &EntryMeta::default()).unwrap().is_none());
+        writer.write_all(b"partial").unwrap();
+        drop(writer);
+        // Interrupted write leaves nothing behind.
+        assert!(!cache.contains(hash));
+        assert_eq!(fs::read_dir(temp.path().join("01")).unwrap().count(), 0);
+
+        let mut writer = cache.put(hash, &EntryMeta::default()).unwrap().unwrap();
+        writer.write_all(b"complete").unwrap();
+        writer.commit().unwrap();
+        assert!(cache.contains(hash));
+        // Stored entry is not overwritten.
+        assert!(cache.put(hash, &EntryMeta::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_cleanup() {
+        let temp = tempfile::tempdir().unwrap();
+        let cache = FileCache {
+            cache_limit: 10,
+            ..FileCache::new(&Config {
+                cache: temp.path().to_path_buf(),
+                ..Config::default()
+            })
+        };
+        for hash in ["0000", "1111", "2222"] {
+            let mut writer = cache.put(hash, &EntryMeta::default()).unwrap().unwrap();
+            writer.write_all(b"12345").unwrap();
+            writer.commit().unwrap();
+        }
+        // Index is rebuilt from disk if lost.
+        fs::remove_file(temp.path().join("index.journal")).unwrap();
+        cache.cleanup().unwrap();
+        assert_eq!(cache.entries().unwrap().len(), 2);
+
+        // Index is updated incrementally.
+        let mut writer = cache.put("3333", &EntryMeta::default()).unwrap().unwrap();
+        writer.write_all(b"12345").unwrap();
+        writer.commit().unwrap();
+        assert_eq!(cache.entries().unwrap().len(), 3);
+        cache.cleanup().unwrap();
+        assert_eq!(cache.entries().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: temp.path().to_path_buf(),
+            cache_namespace_limits_mb: BTreeMap::from([("small".to_string(), 0)]),
+            ..Config::default()
+        };
+        let shared = FileCache::new(&config);
+        let small = FileCache::new(&Config {
+            cache_namespace: Some("small".to_string()),
+            ..config.clone()
+        });
+        for cache in [&shared, &small] {
+            let mut writer = cache.put("0000", &EntryMeta::default()).unwrap().unwrap();
+            writer.write_all(b"12345").unwrap();
+            writer.commit().unwrap();
+        }
+        assert_eq!(FileCache::namespaces(temp.path()).unwrap(), vec!["small"]);
+
+        // Entries of namespaces are not seen by shared cache even after index rebuild.
+        fs::remove_file(temp.path().join("index.journal")).unwrap();
+        assert_eq!(shared.entries().unwrap().len(), 1);
+
+        // Every namespace is limited separately.
+        small.cleanup().unwrap();
+        assert!(small.entries().unwrap().is_empty());
+        shared.cleanup().unwrap();
+        assert!(shared.contains("0000"));
+    }
+
+    #[test]
+    fn test_compression() {
+        let temp = tempfile::tempdir().unwrap();
+        let cache = FileCache::new(&Config {
+            cache: temp.path().join("cache"),
+            ..Config::default()
+        });
+        let statistic = Statistic::new();
+        let path = temp.path().join("output.o");
+        for (hash, compression) in [
+            ("0000", CacheCompression::None),
+            ("1111", CacheCompression::Lz4),
+            ("2222", CacheCompression::Zstd),
+        ] {
+            fs::write(&path, hash).unwrap();
+            let format = EntryFormat {
+                compression,
+                compression_level: 1,
+            };
+            let writer = cache.put(hash, &EntryMeta::default()).unwrap().unwrap();
+            write_cache(
+                &statistic,
+                writer,
+                vec![path.clone()],
+                &test_output(),
+                &format,
+            )
+            .unwrap();
+            fs::remove_file(&path).unwrap();
+
+            let content = inspect_cache(hash, cache.open(hash).unwrap().unwrap(), &cache).unwrap();
+            assert_eq!(content.files.len(), 1);
+            assert_eq!(content.files[0].size, 4);
+            let entry = cache.get(hash).unwrap().unwrap();
+            let output =
+                read_cache(&statistic, hash, entry, std::slice::from_ref(&path), &cache).unwrap();
+            assert_eq!(output.stderr, b"stderr");
+            assert_eq!(fs::read_to_string(&path).unwrap(), hash);
+        }
+    }
+
+    #[test]
+    fn test_blobs() {
+        let temp = tempfile::tempdir().unwrap();
+        let cache = FileCache::new(&Config {
+            cache: temp.path().join("cache"),
+            ..Config::default()
+        });
+        let statistic = Statistic::new();
+        let path = temp.path().join("output.o");
+        let content = vec![7; BLOB_MIN_SIZE as usize];
+        for hash in ["0000", "1111"] {
+            fs::write(&path, &content).unwrap();
+            let writer = cache.put(hash, &EntryMeta::default()).unwrap().unwrap();
+            write_cache(
+                &statistic,
+                writer,
+                vec![path.clone()],
+                &test_output(),
+                &EntryFormat::new(&Config::default()),
+            )
+            .unwrap();
+        }
+        // Identical outputs are stored once.
+        let blobs = cache.blobs.scan().unwrap();
+        assert_eq!(blobs.len(), 1);
+        let entry = cache.get("1111").unwrap().unwrap();
+        let files = inspect_cache("1111", entry, &cache).unwrap().files;
+        assert_eq!(files[0].blob.as_ref(), Some(&blobs[0].0));
+
+        // Copy doesn't depend on blob store.
+        let remote = FileCache::new(&Config {
+            cache: temp.path().join("remote"),
+            ..Config::default()
+        });
+        repack_cache(
+            "1111",
+            cache.get("1111").unwrap().unwrap(),
+            &cache,
+            remote.put("1111", &EntryMeta::default()).unwrap().unwrap(),
+            &EntryFormat::new(&Config::default()),
+        )
+        .unwrap();
+        assert!(entry_blobs("1111", remote.get("1111").unwrap().unwrap())
+            .unwrap()
+            .is_empty());
+        fs::remove_file(&path).unwrap();
+        let entry = remote.get("1111").unwrap().unwrap();
+        read_cache(
+            &statistic,
+            "1111",
+            entry,
+            std::slice::from_ref(&path),
+            &remote,
+        )
+        .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), content);
+
+        // Blob is removed along with the last entry referencing it.
+        cache.evict("0000").unwrap();
+        cache.cleanup().unwrap();
+        assert_eq!(cache.blobs.scan().unwrap().len(), 1);
+        cache.evict("1111").unwrap();
+        cache.cleanup().unwrap();
+        assert!(cache.blobs.scan().unwrap().is_empty());
+
+        // Entry is not committed if reused blob was removed by cleanup meanwhile.
+        let format = EntryFormat::new(&Config::default());
+        let writer = cache.put("2222", &EntryMeta::default()).unwrap().unwrap();
+        write_cache(
+            &statistic,
+            writer,
+            vec![path.clone()],
+            &test_output(),
+            &format,
+        )
+        .unwrap();
+        let mut writer = cache.put("3333", &EntryMeta::default()).unwrap().unwrap();
+        assert!(!writer.put_blob(&path, &format).unwrap().unwrap().created);
+        cache.evict("2222").unwrap();
+        cache.cleanup().unwrap();
+        assert!(writer.commit().is_err());
+        assert!(!cache.contains("3333"));
+    }
+
+    #[test]
+    fn test_raw_blobs() {
+        let temp = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: temp.path().join("cache"),
+            cache_compression: CacheCompression::None,
+            ..Config::default()
+        };
+        let cache = FileCache::new(&config);
+        let statistic = Statistic::new();
+        let path = temp.path().join("output.o");
+        let content = vec![7; BLOB_MIN_SIZE as usize];
+        fs::write(&path, &content).unwrap();
+        let writer = cache.put("0000", &EntryMeta::default()).unwrap().unwrap();
+        write_cache(
+            &statistic,
+            writer,
+            vec![path.clone()],
+            &test_output(),
+            &EntryFormat::new(&config),
+        )
+        .unwrap();
+        let blobs = cache.blobs.scan().unwrap();
+        assert_eq!(blobs.len(), 1);
+        assert!(cache.blobs.raw_path(&blobs[0].0).is_file());
+
+        // Restored by reflink or copy, either way content must match.
+        fs::write(&path, b"garbage").unwrap();
+        let entry = cache.get("0000").unwrap().unwrap();
+        read_cache(
+            &statistic,
+            "0000",
+            entry,
+            std::slice::from_ref(&path),
+            &cache,
+        )
+        .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), content);
+
+        cache.evict("0000").unwrap();
+        cache.cleanup().unwrap();
+        assert!(cache.blobs.scan().unwrap().is_empty());
+        assert_eq!(fs::read(&path).unwrap(), content);
+    }
+
+    #[test]
+    fn test_verify_restored_blob() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("output.o");
+        fs::write(&path, b"content").unwrap();
+        let checksum = crc32fast::hash(b"content");
+        assert!(!verify_restored_blob("verify0", &path, checksum + 1).unwrap());
+        assert!(verify_restored_blob("verify0", &path, checksum).unwrap());
+        // Verified blob is not read again.
+        fs::write(&path, b"garbage").unwrap();
+        assert!(verify_restored_blob("verify0", &path, checksum).unwrap());
+        assert!(!verify_restored_blob("verify1", &path, checksum).unwrap());
+    }
+
+    #[test]
+    fn test_read_legacy() {
+        let mut entry = lz4::EncoderBuilder::new().build(Vec::new()).unwrap();
+        entry.write_all(LEGACY_HEADER).unwrap();
+        write_usize(&mut entry, 0).unwrap();
+        write_output(&mut entry, &test_output()).unwrap();
+        entry.write_all(FOOTER).unwrap();
+        let (entry, result) = entry.finish();
+        result.unwrap();
+
+        let temp = tempfile::tempdir().unwrap();
+        let cache = FileCache::new(&Config {
+            cache: temp.path().to_path_buf(),
+            ..Config::default()
+        });
+        let output = read_cache(&Statistic::new(), "0000", entry.as_slice(), &[], &cache).unwrap();
+        assert_eq!(output.stdout, b"stdout");
+        assert!(read_cache(&Statistic::new(), "0000", &entry[1..], &[], &cache).is_err());
+    }
+}
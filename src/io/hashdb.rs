@@ -0,0 +1,180 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::cache::FileHash;
+
+const HASH_DB_DIR: &str = "hashes";
+const TEMP_PREFIX: &str = "~tmp~";
+// Smaller files are hashed faster than their record is looked up.
+const HASH_DB_MIN_SIZE: u64 = 256 * 1024;
+// Files modified shortly before hashing may change again without changing their timestamp.
+const MODIFIED_MARGIN: Duration = Duration::from_secs(2);
+
+// Persistent content hashes of large input files (like precompiled headers), shared between processes.
+//
+// Every record is stored in a separate file named by hash of the file path and is replaced atomically,
+// so no locking is needed. Records are validated by file size and modification time before use.
+pub struct HashDb {
+    dir: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HashRecord {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+    hash: String,
+}
+
+impl HashDb {
+    #[must_use]
+    pub fn new(cache_dir: &Path) -> Self {
+        HashDb {
+            dir: cache_dir.join(HASH_DB_DIR),
+        }
+    }
+
+    // Hash of file stored by previous processes (not validated).
+    #[must_use]
+    pub fn get(&self, path: &Path) -> Option<FileHash> {
+        let record: HashRecord =
+            bincode::deserialize(&fs::read(self.record_path(path)).ok()?).ok()?;
+        (record.path == path).then_some(FileHash {
+            hash: record.hash,
+            size: record.size,
+            modified: record.modified,
+        })
+    }
+
+    // Remember hash of file computed after `started` time.
+    // Small and recently modified files are skipped.
+    pub fn put(&self, path: &Path, value: &FileHash, started: SystemTime) -> crate::Result<()> {
+        if value.size < HASH_DB_MIN_SIZE || value.modified + MODIFIED_MARGIN >= started {
+            return Ok(());
+        }
+        let data = bincode::serialize(&HashRecord {
+            path: path.to_path_buf(),
+            size: value.size,
+            modified: value.modified,
+            hash: value.hash.clone(),
+        })?;
+        let record_path = self.record_path(path);
+        let parent = record_path.parent().unwrap();
+        fs::create_dir_all(parent)?;
+        let mut temp = tempfile::Builder::new()
+            .prefix(TEMP_PREFIX)
+            .tempfile_in(parent)?;
+        temp.write_all(&data)?;
+        temp.persist(&record_path)
+            .map_err(|e| crate::Error::IO(e.error))?;
+        Ok(())
+    }
+
+    // Remove records of deleted and modified files.
+    // Returns count of removed records.
+    pub fn cleanup(&self) -> crate::Result<usize> {
+        if !self.dir.is_dir() {
+            return Ok(0);
+        }
+        let mut removed = 0;
+        for shard in fs::read_dir(&self.dir)? {
+            let shard = shard?.path();
+            if !shard.is_dir() {
+                continue;
+            }
+            for record_path in fs::read_dir(&shard)? {
+                let record_path = record_path?.path();
+                // Record being written by another process.
+                if record_path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with(TEMP_PREFIX))
+                {
+                    continue;
+                }
+                let valid = fs::read(&record_path)
+                    .ok()
+                    .and_then(|data| bincode::deserialize::<HashRecord>(&data).ok())
+                    .is_some_and(|record| {
+                        fs::metadata(&record.path).is_ok_and(|stat| {
+                            stat.len() == record.size
+                                && stat
+                                    .modified()
+                                    .is_ok_and(|modified| modified == record.modified)
+                        })
+                    });
+                if !valid && fs::remove_file(&record_path).is_ok() {
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    fn record_path(&self, path: &Path) -> PathBuf {
+        let hash = hex::encode(Sha256::digest(path.to_string_lossy().as_bytes()));
+        self.dir.join(&hash[0..2]).join(&hash[2..])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::fs::File;
+    use std::time::{Duration, SystemTime};
+
+    use crate::cache::{Cache, FileHash, FileHasher};
+    use crate::config::Config;
+    use crate::io::hashdb::{HashDb, HASH_DB_MIN_SIZE};
+
+    #[test]
+    fn test_hash_db() {
+        let temp = tempfile::tempdir().unwrap();
+        let db = HashDb::new(temp.path());
+        let path = temp.path().join("stdafx.pch");
+        fs::write(&path, vec![1; HASH_DB_MIN_SIZE as usize]).unwrap();
+        let modified = SystemTime::now() - Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let value = FileHash {
+            hash: "fake".to_string(),
+            size: HASH_DB_MIN_SIZE,
+            modified,
+        };
+
+        // Recently modified file is not trusted.
+        db.put(&path, &value, modified).unwrap();
+        assert!(db.get(&path).is_none());
+
+        // Persisted value is reused by new processes while file is unchanged.
+        db.put(&path, &value, SystemTime::now()).unwrap();
+        assert_eq!(db.get(&path).unwrap().hash, "fake");
+        let config = Config {
+            cache: temp.path().to_path_buf(),
+            ..Config::default()
+        };
+        assert_eq!(
+            Cache::new(&config).unwrap().file_hash(&path).unwrap().hash,
+            "fake"
+        );
+        assert_eq!(db.cleanup().unwrap(), 0);
+
+        fs::write(&path, vec![2; HASH_DB_MIN_SIZE as usize]).unwrap();
+        assert_ne!(
+            Cache::new(&config).unwrap().file_hash(&path).unwrap().hash,
+            "fake"
+        );
+        // Record of modified file is removed when cache is flushed.
+        assert!(db.get(&path).is_none());
+        assert_eq!(db.cleanup().unwrap(), 0);
+    }
+}
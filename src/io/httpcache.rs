@@ -0,0 +1,151 @@
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+
+use reqwest::blocking::{Body, Client};
+use reqwest::{StatusCode, Url};
+
+use crate::cache::{CacheBackend, CacheEntryWriter, EntryMeta};
+use crate::config::{CacheRemoteMode, Config};
+
+// Remote cache storage accessed via plain HTTP GET/PUT/HEAD/DELETE requests.
+//
+// Entries are stored as `<base>/<hash[0..2]>/<hash>` using the same packed format as `FileCache`.
+pub struct HttpCache {
+    base_url: Url,
+    mode: CacheRemoteMode,
+    client: Client,
+}
+
+struct HttpEntryWriter {
+    url: Url,
+    client: Client,
+    temp: File,
+}
+
+impl HttpCache {
+    pub fn new(config: &Config) -> std::io::Result<Self> {
+        let base_url = config.cache_remote_url.clone().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "cache_remote_url is required for remote cache",
+            )
+        })?;
+        Ok(HttpCache {
+            base_url,
+            mode: config.cache_remote_mode,
+            client: Client::new(),
+        })
+    }
+
+    fn entry_url(&self, hash: &str) -> Url {
+        let mut url = self.base_url.clone();
+        url.path_segments_mut()
+            .expect("cache_remote_url can't be a base")
+            .pop_if_empty()
+            .push(&hash[0..2])
+            .push(hash);
+        url
+    }
+}
+
+impl CacheBackend for HttpCache {
+    fn get(&self, hash: &str) -> crate::Result<Option<Box<dyn Read>>> {
+        let response = self.client.get(self.entry_url(hash)).send()?;
+        match response.status() {
+            StatusCode::OK => Ok(Some(Box::new(response))),
+            StatusCode::NOT_FOUND => Ok(None),
+            status => Err(crate::Error::Generic(format!(
+                "Can't download cache entry {hash}: {status}"
+            ))),
+        }
+    }
+
+    fn put(&self, hash: &str, _: &EntryMeta) -> crate::Result<Option<Box<dyn CacheEntryWriter>>> {
+        Ok(Some(Box::new(HttpEntryWriter {
+            url: self.entry_url(hash),
+            client: self.client.clone(),
+            temp: tempfile::tempfile()?,
+        })))
+    }
+
+    fn writable(&self) -> bool {
+        self.mode == CacheRemoteMode::ReadWrite
+    }
+
+    fn contains(&self, hash: &str) -> bool {
+        self.client
+            .head(self.entry_url(hash))
+            .send()
+            .is_ok_and(|response| response.status() == StatusCode::OK)
+    }
+
+    fn evict(&self, hash: &str) -> crate::Result<()> {
+        if !self.writable() {
+            return Ok(());
+        }
+        match self.client.delete(self.entry_url(hash)).send()?.status() {
+            status if status.is_success() || status == StatusCode::NOT_FOUND => Ok(()),
+            status => Err(crate::Error::Generic(format!(
+                "Can't remove cache entry {hash}: {status}"
+            ))),
+        }
+    }
+
+    fn cleanup(&self) -> crate::Result<()> {
+        // Remote storage size is managed by the server.
+        Ok(())
+    }
+}
+
+impl Write for HttpEntryWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.temp.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.temp.flush()
+    }
+}
+
+impl CacheEntryWriter for HttpEntryWriter {
+    fn commit(mut self: Box<Self>) -> crate::Result<()> {
+        let size = self.temp.stream_position()?;
+        self.temp.rewind()?;
+        let status = self
+            .client
+            .put(self.url)
+            .body(Body::sized(self.temp, size))
+            .send()?
+            .status();
+        if !status.is_success() {
+            return Err(crate::Error::Generic(format!(
+                "Can't upload cache entry: {status}"
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::Config;
+    use crate::io::httpcache::HttpCache;
+
+    #[test]
+    fn test_entry_url() {
+        for base in [
+            "http://cache.local/octobuild",
+            "http://cache.local/octobuild/",
+        ] {
+            let cache = HttpCache::new(&Config {
+                cache_remote_url: Some(base.parse().unwrap()),
+                ..Config::default()
+            })
+            .unwrap();
+            assert_eq!(
+                cache.entry_url("0123abcd").as_str(),
+                "http://cache.local/octobuild/01/0123abcd"
+            );
+        }
+    }
+}
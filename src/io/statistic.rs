@@ -1,15 +1,80 @@
 use std::cmp::max;
-
+use std::collections::BTreeMap;
 use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, Seek};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::CacheMode;
+use crate::io::cacheindex::unix_time;
+use crate::utils::{lock_file, replace_file};
 
-#[derive(Default)]
+const STATISTIC_NAME: &str = "stats";
+const STATISTIC_VERSION: u32 = 1;
+
+#[derive(Default, Serialize, Deserialize)]
 pub struct Statistic {
     pub hit_count: AtomicUsize,
     pub hit_bytes: AtomicUsize,
     pub miss_count: AtomicUsize,
     pub miss_bytes: AtomicUsize,
+    // Successful remote compilations.
     pub remote_count: AtomicUsize,
+    pub remote_attempt_count: AtomicUsize,
+    // Local compilations after failed remote attempt.
+    pub remote_fallback_count: AtomicUsize,
+    pub remote_hit_count: AtomicUsize,
+    // Hits found without preprocessing.
+    pub direct_hit_count: AtomicUsize,
+    // Hits produced by identical compilation running at the same time.
+    pub in_flight_hit_count: AtomicUsize,
+    pub corrupt_count: AtomicUsize,
+    pub error_count: AtomicUsize,
+    pub write_error_count: AtomicUsize,
+    pub preprocess_micros: AtomicUsize,
+    pub compile_micros: AtomicUsize,
+    // Tasks executed without cache by reason.
+    pub uncacheable: Mutex<BTreeMap<String, usize>>,
+    // Cached tasks by toolchain identifier.
+    pub toolchains: Mutex<BTreeMap<String, ToolchainStatistic>>,
+    // Cached tasks by cache mode.
+    pub cache_modes: Mutex<BTreeMap<String, usize>>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ToolchainStatistic {
+    pub hit_count: usize,
+    pub miss_count: usize,
+    pub error_count: usize,
+}
+
+// Reason of executing task without cache.
+#[derive(Clone, Copy, Debug)]
+pub enum Uncacheable {
+    // Command is not a supported compiler.
+    UnsupportedCommand,
+    // Compiler arguments can't be handled.
+    UnsupportedArguments,
+    PreprocessFailed,
+}
+
+// Cumulative statistic of all processes sharing the same cache directory.
+pub struct StatisticFile {
+    path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredStatistic {
+    version: u32,
+    // Time of last reset (seconds since Unix epoch).
+    since: u64,
+    statistic: Statistic,
 }
 
 impl fmt::Display for Statistic {
@@ -19,18 +84,35 @@ impl fmt::Display for Statistic {
         let miss_count = self.miss_count.load(Ordering::Relaxed);
         let miss_bytes = self.miss_bytes.load(Ordering::Relaxed);
         let remote_count = self.remote_count.load(Ordering::Relaxed);
+        let remote_hit_count = self.remote_hit_count.load(Ordering::Relaxed);
+        let direct_hit_count = self.direct_hit_count.load(Ordering::Relaxed);
+        let in_flight_hit_count = self.in_flight_hit_count.load(Ordering::Relaxed);
+        let corrupt_count = self.corrupt_count.load(Ordering::Relaxed);
+        let error_count = self.error_count.load(Ordering::Relaxed);
         let total_count = hit_count + miss_count;
         write!(
             f,
-            "Cache statistic: hit {} of {} ({} %), remote {}, read {}, write {}, total {}",
+            "Cache statistic: hit {} of {} ({} %), direct hit {}, in-flight hit {}, remote hit {}, remote {}, corrupt {}, errors {}, read {}, write {}, total {}",
             hit_count,
             total_count,
             hit_count * 100 / max(total_count, 1),
+            direct_hit_count,
+            in_flight_hit_count,
+            remote_hit_count,
             remote_count,
+            corrupt_count,
+            error_count,
             hit_bytes,
             miss_bytes,
             hit_bytes + miss_bytes,
-        )
+        )?;
+        // Only unusual modes are shown.
+        for (mode, count) in self.cache_modes.lock().unwrap().iter() {
+            if mode != CacheMode::Normal.name() {
+                write!(f, ", {mode} {count}")?;
+            }
+        }
+        Ok(())
     }
 }
 
@@ -53,4 +135,242 @@ impl Statistic {
     pub fn inc_remote(&self) {
         self.remote_count.fetch_add(1, Ordering::Release);
     }
+
+    pub fn inc_remote_attempt(&self) {
+        self.remote_attempt_count.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn inc_remote_fallback(&self) {
+        self.remote_fallback_count.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn inc_write_error(&self) {
+        self.write_error_count.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn add_preprocess_time(&self, duration: Duration) {
+        self.preprocess_micros
+            .fetch_add(duration.as_micros() as usize, Ordering::Release);
+    }
+
+    pub fn add_compile_time(&self, duration: Duration) {
+        self.compile_micros
+            .fetch_add(duration.as_micros() as usize, Ordering::Release);
+    }
+
+    pub fn inc_cache_mode(&self, mode: CacheMode) {
+        *self
+            .cache_modes
+            .lock()
+            .unwrap()
+            .entry(mode.name().to_string())
+            .or_default() += 1;
+    }
+
+    pub fn inc_uncacheable(&self, reason: Uncacheable) {
+        *self
+            .uncacheable
+            .lock()
+            .unwrap()
+            .entry(reason.name().to_string())
+            .or_default() += 1;
+    }
+
+    pub fn update_toolchain<F: FnOnce(&mut ToolchainStatistic)>(&self, toolchain: &str, func: F) {
+        func(
+            self.toolchains
+                .lock()
+                .unwrap()
+                .entry(toolchain.to_string())
+                .or_default(),
+        );
+    }
+
+    pub fn to_json(&self) -> crate::Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn inc_remote_hit(&self) {
+        self.remote_hit_count.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn inc_direct_hit(&self) {
+        self.direct_hit_count.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn inc_in_flight_hit(&self) {
+        self.in_flight_hit_count.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn inc_corrupt(&self) {
+        self.corrupt_count.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn inc_error(&self) {
+        self.error_count.fetch_add(1, Ordering::Release);
+    }
+
+    // Add counters of other statistic to this one.
+    pub fn add(&self, other: &Statistic) {
+        for (counter, value) in [
+            (&self.hit_count, &other.hit_count),
+            (&self.hit_bytes, &other.hit_bytes),
+            (&self.miss_count, &other.miss_count),
+            (&self.miss_bytes, &other.miss_bytes),
+            (&self.remote_count, &other.remote_count),
+            (&self.remote_attempt_count, &other.remote_attempt_count),
+            (&self.remote_fallback_count, &other.remote_fallback_count),
+            (&self.remote_hit_count, &other.remote_hit_count),
+            (&self.direct_hit_count, &other.direct_hit_count),
+            (&self.in_flight_hit_count, &other.in_flight_hit_count),
+            (&self.corrupt_count, &other.corrupt_count),
+            (&self.error_count, &other.error_count),
+            (&self.write_error_count, &other.write_error_count),
+            (&self.preprocess_micros, &other.preprocess_micros),
+            (&self.compile_micros, &other.compile_micros),
+        ] {
+            counter.fetch_add(value.load(Ordering::Acquire), Ordering::Release);
+        }
+        let mut uncacheable = self.uncacheable.lock().unwrap();
+        for (reason, count) in other.uncacheable.lock().unwrap().iter() {
+            *uncacheable.entry(reason.clone()).or_default() += count;
+        }
+        let mut toolchains = self.toolchains.lock().unwrap();
+        for (toolchain, value) in other.toolchains.lock().unwrap().iter() {
+            let entry = toolchains.entry(toolchain.clone()).or_default();
+            entry.hit_count += value.hit_count;
+            entry.miss_count += value.miss_count;
+            entry.error_count += value.error_count;
+        }
+        let mut cache_modes = self.cache_modes.lock().unwrap();
+        for (mode, count) in other.cache_modes.lock().unwrap().iter() {
+            *cache_modes.entry(mode.clone()).or_default() += count;
+        }
+    }
+}
+
+impl Uncacheable {
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Uncacheable::UnsupportedCommand => "unsupported_command",
+            Uncacheable::UnsupportedArguments => "unsupported_arguments",
+            Uncacheable::PreprocessFailed => "preprocess_failed",
+        }
+    }
+}
+
+impl StatisticFile {
+    #[must_use]
+    pub fn new(dir: &Path) -> Self {
+        StatisticFile {
+            path: dir.join(STATISTIC_NAME),
+        }
+    }
+
+    // Load stored statistic along with time of its last reset.
+    pub fn load(&self) -> crate::Result<(Statistic, SystemTime)> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok((Statistic::new(), SystemTime::now()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let stored = read_stored(&file)?;
+        Ok((
+            stored.statistic,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(stored.since),
+        ))
+    }
+
+    // Atomically add process statistic to stored one.
+    pub fn add(&self, statistic: &Statistic) -> crate::Result<()> {
+        self.modify(|stored| stored.statistic.add(statistic))
+    }
+
+    // Zero stored statistic.
+    pub fn reset(&self) -> crate::Result<()> {
+        self.modify(|stored| *stored = StoredStatistic::new())
+    }
+
+    // File is replaced atomically, so readers don't need a lock.
+    fn modify<F: FnOnce(&mut StoredStatistic)>(&self, func: F) -> crate::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let _lock = lock_file(&self.path)?;
+        let mut stored = match File::open(&self.path) {
+            Ok(file) => read_stored(&file)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => StoredStatistic::new(),
+            Err(e) => return Err(e.into()),
+        };
+        func(&mut stored);
+        replace_file(&self.path, &bincode::serialize(&stored)?)
+    }
+}
+
+impl StoredStatistic {
+    fn new() -> Self {
+        StoredStatistic {
+            version: STATISTIC_VERSION,
+            since: unix_time(SystemTime::now()),
+            statistic: Statistic::new(),
+        }
+    }
+}
+
+// Empty, damaged or outdated file is treated as zero statistic.
+fn read_stored(mut file: &File) -> crate::Result<StoredStatistic> {
+    file.rewind()?;
+    Ok(bincode::deserialize_from(BufReader::new(file))
+        .ok()
+        .filter(|stored: &StoredStatistic| stored.version == STATISTIC_VERSION)
+        .unwrap_or_else(StoredStatistic::new))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::Ordering;
+
+    use crate::config::CacheMode;
+    use crate::io::statistic::{Statistic, StatisticFile, Uncacheable};
+
+    #[test]
+    fn test_statistic_file() {
+        let temp = tempfile::tempdir().unwrap();
+        let file = StatisticFile::new(temp.path());
+        let statistic = Statistic::new();
+        statistic.add_hit(10);
+        statistic.add_miss(20);
+        statistic.update_toolchain("clang", |s| s.hit_count += 1);
+        file.add(&statistic).unwrap();
+        file.add(&statistic).unwrap();
+
+        let (stored, since) = file.load().unwrap();
+        assert_eq!(stored.hit_count.load(Ordering::Relaxed), 2);
+        assert_eq!(stored.miss_bytes.load(Ordering::Relaxed), 40);
+        assert_eq!(stored.toolchains.lock().unwrap()["clang"].hit_count, 2);
+
+        file.reset().unwrap();
+        let (stored, reset) = file.load().unwrap();
+        assert_eq!(stored.hit_count.load(Ordering::Relaxed), 0);
+        assert!(reset >= since);
+    }
+
+    #[test]
+    fn test_statistic_json() {
+        let statistic = Statistic::new();
+        statistic.add_hit(10);
+        statistic.inc_uncacheable(Uncacheable::PreprocessFailed);
+        statistic.update_toolchain("clang", |s| s.miss_count += 1);
+        statistic.inc_cache_mode(CacheMode::Recache);
+        assert!(statistic.to_string().ends_with(", recache 1"));
+
+        let json: serde_json::Value = serde_json::from_str(&statistic.to_json().unwrap()).unwrap();
+        assert_eq!(json["hit_count"], 1);
+        assert_eq!(json["uncacheable"]["preprocess_failed"], 1);
+        assert_eq!(json["toolchains"]["clang"]["miss_count"], 1);
+        assert_eq!(json["cache_modes"]["recache"], 1);
+    }
 }
@@ -6,6 +6,7 @@ use thiserror::Error;
 use crate::io::filecache::CacheError;
 use crate::vs::postprocess::PostprocessError;
 
+pub mod basedirs;
 pub mod cache;
 
 pub mod cluster {
@@ -16,14 +17,24 @@ pub mod cluster {
 
 pub mod compiler;
 pub mod config;
+pub mod direct;
+pub mod durations;
+pub mod explain;
+pub mod inflight;
 pub mod lazy;
+pub mod linemarker;
+pub mod trace;
 pub mod utils;
 pub mod version;
 
 pub mod io {
     pub mod binary;
+    pub mod bundle;
+    pub mod cacheindex;
     pub mod counter;
     pub mod filecache;
+    pub mod hashdb;
+    pub mod httpcache;
     pub mod memcache;
     pub mod memstream;
     pub mod statistic;
@@ -56,6 +67,11 @@ pub mod worker;
 pub enum Error {
     #[error(transparent)]
     Bincode(#[from] bincode::Error),
+    #[error("Build failed: {} tasks failed, {} tasks skipped", .failed.len(), .skipped.len())]
+    BuildFailed {
+        failed: Vec<String>,
+        skipped: Vec<String>,
+    },
     #[error(transparent)]
     Cache(#[from] CacheError),
     #[error("Found cycles in build graph")]
@@ -77,6 +93,8 @@ pub enum Error {
     Generic(String),
     #[error(transparent)]
     IO(std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
     #[error("Build task files not found")]
     NoTaskFiles,
     #[error("Failed to compile {path}: {error}")]
@@ -99,6 +117,15 @@ pub enum Error {
 
 impl From<std::io::Error> for Error {
     fn from(value: std::io::Error) -> Self {
+        // Cache errors may be passed through readers as IO errors.
+        if value.get_ref().is_some_and(|e| e.is::<CacheError>()) {
+            let error = value
+                .into_inner()
+                .unwrap()
+                .downcast::<CacheError>()
+                .unwrap();
+            return Error::Cache(*error);
+        }
         IO(value)
     }
 }
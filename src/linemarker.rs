@@ -0,0 +1,79 @@
+use std::borrow::Cow;
+use std::sync::OnceLock;
+
+use regex::bytes::{Captures, Regex};
+
+// Matches clang (`# 1 "file"`) and MSVC (`#line 1 "file"`) line markers of preprocessed output.
+fn re_line_marker() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(r#"(?m)^[ \t]*#[ \t]*(?:line[ \t]+)?\d+[ \t]+"((?:[^"\\\r\n]|\\.)*)""#).unwrap()
+    })
+}
+
+// File names referenced by line markers in order of appearance.
+pub fn file_names(preprocessed: &[u8]) -> impl Iterator<Item = String> + '_ {
+    re_line_marker()
+        .captures_iter(preprocessed)
+        .map(|cap| unescape(&cap[1]))
+}
+
+// Replace file names of line markers, keeping markers for which `func` returns None untouched.
+pub fn rewrite<F>(preprocessed: &[u8], func: F) -> Cow<'_, [u8]>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    re_line_marker().replace_all(preprocessed, |cap: &Captures| {
+        let whole = cap.get(0).unwrap();
+        let name = cap.get(1).unwrap();
+        match func(&unescape(name.as_bytes())) {
+            Some(replaced) => {
+                let mut result = whole.as_bytes()[..name.start() - whole.start()].to_vec();
+                result.extend_from_slice(escape(&replaced).as_bytes());
+                result.extend_from_slice(&whole.as_bytes()[name.end() - whole.start()..]);
+                result
+            }
+            None => whole.as_bytes().to_vec(),
+        }
+    })
+}
+
+fn unescape(name: &[u8]) -> String {
+    let mut result = Vec::with_capacity(name.len());
+    let mut iter = name.iter();
+    while let Some(&c) = iter.next() {
+        if c == b'\\' {
+            if let Some(&next) = iter.next() {
+                result.push(next);
+                continue;
+            }
+        }
+        result.push(c);
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+fn escape(name: &str) -> String {
+    name.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod test {
+    use crate::linemarker::{file_names, rewrite};
+
+    #[test]
+    fn test_rewrite() {
+        let preprocessed = b"# 1 \"main.cpp\"\n#line 2 \"C:\\\\include\\\\a.h\"\nint x;\n";
+        assert_eq!(
+            file_names(preprocessed).collect::<Vec<_>>(),
+            vec!["main.cpp", "C:\\include\\a.h"]
+        );
+        assert_eq!(
+            rewrite(preprocessed, |name| name
+                .strip_prefix("C:\\include\\")
+                .map(|rest| format!(".\\{rest}")))
+            .as_ref(),
+            b"# 1 \"main.cpp\"\n#line 2 \".\\\\a.h\"\nint x;\n"
+        );
+    }
+}
@@ -73,6 +73,7 @@ where
         CommandArgs::Regular(args),
         exec,
         config.run_second_cpp,
+        &state.statistic,
     );
 
     let mut build_graph: BuildGraph = Graph::new();
@@ -82,7 +83,14 @@ where
             action,
         }));
     }
-    let result = execute_graph(state, build_graph, config.process_limit, print_task_result);
+    let result = execute_graph(
+        state,
+        build_graph,
+        config.process_limit,
+        config.keep_going,
+        print_task_result,
+    );
+    state.save_statistic();
     println!("{}", state.statistic);
     result
 }
@@ -0,0 +1,229 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use serde::Serialize;
+
+thread_local! {
+    static CURRENT: RefCell<Option<Context>> = const { RefCell::new(None) };
+}
+
+// Timeline of build in Chrome trace event format (chrome://tracing, https://ui.perfetto.dev).
+//
+// Every thread records its spans to own lane. Spans are recorded through thread local context,
+// so steps deep inside compilation don't need access to the trace.
+pub struct Trace {
+    path: PathBuf,
+    start: Instant,
+    events: Mutex<Vec<TraceEvent>>,
+    lanes: Mutex<BTreeMap<usize, String>>,
+}
+
+#[derive(Serialize)]
+struct TraceEvent {
+    name: String,
+    ph: &'static str,
+    // Microseconds since start of build.
+    ts: u128,
+    dur: u128,
+    pid: u32,
+    tid: usize,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    args: BTreeMap<&'static str, String>,
+}
+
+struct Context {
+    trace: Arc<Trace>,
+    lane: usize,
+    spans: Vec<OpenSpan>,
+}
+
+struct OpenSpan {
+    name: String,
+    start: Instant,
+    args: BTreeMap<&'static str, String>,
+}
+
+// Records current thread to lane until dropped.
+pub struct LaneGuard {
+    previous: Option<Context>,
+}
+
+// Slice of timeline, recorded when dropped.
+pub struct Span {
+    active: bool,
+}
+
+impl Trace {
+    #[must_use]
+    pub fn new(path: &Path) -> Self {
+        Trace {
+            path: path.to_path_buf(),
+            start: Instant::now(),
+            events: Mutex::default(),
+            lanes: Mutex::default(),
+        }
+    }
+
+    // Record spans of current thread to lane with given id.
+    pub fn enter(self: &Arc<Self>, lane: usize, name: impl FnOnce() -> String) -> LaneGuard {
+        self.lanes.lock().unwrap().entry(lane).or_insert_with(name);
+        let context = Context {
+            trace: self.clone(),
+            lane,
+            spans: Vec::new(),
+        };
+        LaneGuard {
+            previous: CURRENT.with(|current| current.replace(Some(context))),
+        }
+    }
+
+    pub fn write(&self) -> crate::Result<()> {
+        let pid = std::process::id();
+        let lanes = self
+            .lanes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(lane, name)| TraceEvent {
+                name: "thread_name".to_string(),
+                ph: "M",
+                ts: 0,
+                dur: 0,
+                pid,
+                tid: *lane,
+                args: BTreeMap::from([("name", name.clone())]),
+            })
+            .collect::<Vec<_>>();
+        let events = self.events.lock().unwrap();
+        let mut writer = BufWriter::new(File::create(&self.path)?);
+        serde_json::to_writer(
+            &mut writer,
+            &serde_json::json!({
+                "traceEvents": lanes.iter().chain(events.iter()).collect::<Vec<_>>(),
+                "displayTimeUnit": "ms",
+            }),
+        )?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for LaneGuard {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.replace(self.previous.take()));
+    }
+}
+
+// Start span on lane of current thread (no-op if thread is not traced).
+pub fn span(name: impl Into<String>) -> Span {
+    let active = CURRENT.with(|current| match current.borrow_mut().as_mut() {
+        Some(context) => {
+            context.spans.push(OpenSpan {
+                name: name.into(),
+                start: Instant::now(),
+                args: BTreeMap::new(),
+            });
+            true
+        }
+        None => false,
+    });
+    Span { active }
+}
+
+// Add argument to the outermost open span of current thread (usually the build task).
+pub fn annotate(key: &'static str, value: impl ToString) {
+    CURRENT.with(|current| {
+        if let Some(span) = current
+            .borrow_mut()
+            .as_mut()
+            .and_then(|context| context.spans.first_mut())
+        {
+            span.args.insert(key, value.to_string());
+        }
+    });
+}
+
+// Trace of current thread to continue recording on another thread.
+#[must_use]
+pub fn current() -> Option<Arc<Trace>> {
+    CURRENT.with(|current| {
+        current
+            .borrow()
+            .as_ref()
+            .map(|context| context.trace.clone())
+    })
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if !self.active {
+            return;
+        }
+        CURRENT.with(|current| {
+            let mut current = current.borrow_mut();
+            let Some(context) = current.as_mut() else {
+                return;
+            };
+            let Some(span) = context.spans.pop() else {
+                return;
+            };
+            let trace = &context.trace;
+            let event = TraceEvent {
+                name: span.name,
+                ph: "X",
+                ts: span
+                    .start
+                    .saturating_duration_since(trace.start)
+                    .as_micros(),
+                dur: span.start.elapsed().as_micros(),
+                pid: std::process::id(),
+                tid: context.lane,
+                args: span.args,
+            };
+            trace.events.lock().unwrap().push(event);
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::sync::Arc;
+
+    use crate::trace::{annotate, span, Trace};
+
+    #[test]
+    fn test_trace() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("trace.json");
+        let trace = Arc::new(Trace::new(&path));
+
+        // Thread without lane is not traced.
+        drop(span("ignored"));
+        {
+            let _lane = trace.enter(3, || "worker #3".to_string());
+            let _task = span("main.cpp");
+            let _step = span("cache lookup");
+            annotate("cache", "miss");
+        }
+        drop(span("ignored"));
+        trace.write().unwrap();
+
+        let json: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        let events = json["traceEvents"].as_array().unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0]["ph"], "M");
+        assert_eq!(events[0]["args"]["name"], "worker #3");
+        // Inner span is completed first.
+        assert_eq!(events[1]["name"], "cache lookup");
+        assert_eq!(events[2]["name"], "main.cpp");
+        assert_eq!(events[2]["tid"], 3);
+        assert_eq!(events[2]["args"]["cache"], "miss");
+        assert!(events[1]["ts"].as_u64() >= events[2]["ts"].as_u64());
+    }
+}
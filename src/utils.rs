@@ -1,12 +1,13 @@
 use local_encoding_ng::{Encoder, Encoding};
 use std::ffi::{OsStr, OsString};
 use std::io;
-use std::io::{Error, Read};
-use std::path::PathBuf;
+use std::io::{Error, Read, Write};
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 use std::{env, fs};
 
 use crate::cmd;
+use fs2::FileExt;
 use sha2::{Digest, Sha256};
 
 pub fn hash_stream<R: Read>(reader: &mut R) -> Result<String, Error> {
@@ -82,6 +83,45 @@ fn decode_utf16<F: Fn(u16, u16) -> u16>(data: &[u8], endian: F) -> crate::Result
     Ok(String::from_utf16(&utf16)?)
 }
 
+// Short human-readable form of time interval.
+#[must_use]
+pub fn format_age(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{seconds}s"),
+        60..=3599 => format!("{}m", seconds / 60),
+        3600..=86399 => format!("{}h", seconds / 3600),
+        _ => format!("{}d", seconds / 86400),
+    }
+}
+
+// Take exclusive lock of `<path>.lock`, released when returned file is closed.
+// Used to serialize writers of files replaced by `replace_file`.
+pub fn lock_file(path: &Path) -> crate::Result<fs::File> {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(lock_path)?;
+    file.lock_exclusive()?;
+    Ok(file)
+}
+
+// Replace file content atomically, so it is not lost if process is killed while writing.
+pub fn replace_file(path: &Path, data: &[u8]) -> crate::Result<()> {
+    let parent = path
+        .parent()
+        .ok_or_else(|| format!("Invalid file path: {}", path.display()))?;
+    fs::create_dir_all(parent)?;
+    let mut temp = tempfile::Builder::new()
+        .prefix("~tmp~")
+        .tempfile_in(parent)?;
+    temp.write_all(data)?;
+    temp.persist(path).map_err(|e| crate::Error::IO(e.error))?;
+    Ok(())
+}
+
 pub fn init_logger() {
     let log_file = env::current_exe().unwrap().with_extension("log");
 
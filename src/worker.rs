@@ -1,9 +1,12 @@
 use std::borrow::Cow;
-use std::cmp::{max, min};
+use std::cmp::{max, min, Reverse};
+use std::collections::{BinaryHeap, HashMap};
 use std::io::Write;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+use log::warn;
+use petgraph::algo::toposort;
 use petgraph::graph::NodeIndex;
 use petgraph::{EdgeDirection, Graph};
 
@@ -11,6 +14,9 @@ use crate::compiler::{
     BuildTaskResult, CommandArgs, CommandInfo, CompilationTask, Compiler, OutputInfo, SharedState,
     Toolchain,
 };
+use crate::durations::TaskDurations;
+use crate::io::statistic::{Statistic, Uncacheable};
+use crate::trace;
 
 pub type BuildGraph = Graph<Arc<BuildTask>, ()>;
 
@@ -41,6 +47,10 @@ impl BuildTask {
             duration: Instant::now().duration_since(start_time),
         }
     }
+
+    fn duration_key(&self) -> String {
+        TaskDurations::key(&self.title, &self.action.title())
+    }
 }
 
 pub enum BuildAction {
@@ -74,6 +84,17 @@ struct TaskMessage {
     task: Arc<BuildTask>,
 }
 
+// Ready tasks are queued here and sent to workers only when some of them are idle,
+// so the tasks with the longest chain of dependent tasks are started first.
+struct Scheduler {
+    ready: BinaryHeap<(u128, Reverse<NodeIndex>)>,
+    priorities: Vec<u128>,
+    running: usize,
+    workers: usize,
+    // Durations of succeeded tasks.
+    durations: HashMap<String, Duration>,
+}
+
 impl<'a> BuildResult<'a> {
     fn new(message: &'a ResultMessage, completed: &mut usize, total: usize) -> Self {
         *completed += 1;
@@ -102,6 +123,7 @@ impl BuildAction {
         args: CommandArgs,
         title: &str,
         run_second_cpp: bool,
+        statistic: &Statistic,
     ) -> Vec<BuildAction> {
         let actions: Vec<BuildAction> = compiler
             .create_tasks(command.clone(), args.clone(), run_second_cpp)
@@ -113,8 +135,11 @@ impl BuildAction {
             })
             .unwrap_or_else(|e| {
                 match e {
-                    crate::Error::ToolchainNotFound(_) => {}
+                    crate::Error::ToolchainNotFound(_) => {
+                        statistic.inc_uncacheable(Uncacheable::UnsupportedCommand);
+                    }
                     e => {
+                        statistic.inc_uncacheable(Uncacheable::UnsupportedArguments);
                         println!("Cannot cache task {title}: {e}");
                     }
                 }
@@ -167,49 +192,142 @@ pub fn validate_graph<N, E>(graph: Graph<N, E>) -> crate::Result<Graph<N, E>> {
     Err(crate::Error::CyclesInBuildGraph)
 }
 
+impl Scheduler {
+    fn new(graph: &BuildGraph, durations: &HashMap<String, Duration>, workers: usize) -> Self {
+        Scheduler {
+            ready: BinaryHeap::new(),
+            priorities: critical_paths(graph, durations),
+            running: 0,
+            workers,
+            durations: HashMap::new(),
+        }
+    }
+
+    fn push(&mut self, index: NodeIndex) {
+        self.ready
+            .push((self.priorities[index.index()], Reverse(index)));
+    }
+
+    // Send ready tasks to idle workers.
+    fn dispatch(
+        &mut self,
+        graph: &BuildGraph,
+        tx_task: &crossbeam_channel::Sender<TaskMessage>,
+    ) -> crate::Result<()> {
+        while self.running < self.workers {
+            let Some((_, Reverse(index))) = self.ready.pop() else {
+                break;
+            };
+            tx_task
+                .send(TaskMessage {
+                    index,
+                    task: graph.node_weight(index).unwrap().clone(),
+                })
+                .map_err(crate::Error::send_error)?;
+            self.running += 1;
+        }
+        Ok(())
+    }
+}
+
+// Estimated time in milliseconds from start of each task to the end of build: duration of task
+// plus the longest chain of tasks depending on it.
+fn critical_paths(graph: &BuildGraph, durations: &HashMap<String, Duration>) -> Vec<u128> {
+    let estimates: Vec<Option<u128>> = graph
+        .node_weights()
+        .map(|task| match task.action {
+            BuildAction::Empty => Some(0),
+            _ => durations.get(&task.duration_key()).map(Duration::as_millis),
+        })
+        .collect();
+    // Tasks never built before are assumed to take average time.
+    let known: Vec<u128> = estimates
+        .iter()
+        .flatten()
+        .copied()
+        .filter(|millis| *millis > 0)
+        .collect();
+    let average = max(1, known.iter().sum::<u128>() / max(1, known.len() as u128));
+    let mut paths: Vec<u128> = vec![0; graph.node_count()];
+    // Graph is validated, so it has no cycles. Dependent tasks precede their dependencies.
+    for index in toposort(graph, None).unwrap_or_default() {
+        let tail = graph
+            .neighbors_directed(index, EdgeDirection::Incoming)
+            .map(|source| paths[source.index()])
+            .max()
+            .unwrap_or(0);
+        paths[index.index()] = estimates[index.index()].unwrap_or(average) + tail;
+    }
+    paths
+}
+
+// Run tasks until the first failure or, in keep going mode, until all tasks not depending
+// on failed ones are completed.
 fn execute_until_failed<F>(
     graph: &BuildGraph,
     tx_task: &crossbeam_channel::Sender<TaskMessage>,
     rx_result: &crossbeam_channel::Receiver<ResultMessage>,
+    scheduler: &mut Scheduler,
     count: &mut usize,
+    keep_going: bool,
     update_progress: F,
 ) -> crate::Result<()>
 where
     F: Fn(&BuildResult) -> crate::Result<()>,
 {
     let mut completed: Vec<bool> = vec![false; graph.node_count()];
+    let mut skipped: Vec<bool> = vec![false; graph.node_count()];
+    let mut skipped_count: usize = 0;
+    let mut failed: Vec<NodeIndex> = Vec::new();
     for index in graph.externals(EdgeDirection::Outgoing) {
-        tx_task
-            .send(TaskMessage {
-                index,
-                task: graph.node_weight(index).unwrap().clone(),
-            })
-            .map_err(crate::Error::send_error)?;
+        scheduler.push(index);
     }
+    scheduler.dispatch(graph, tx_task)?;
 
     for message in rx_result {
         assert!(!completed[message.index.index()]);
+        scheduler.running -= 1;
 
         update_progress(&BuildResult::new(&message, count, graph.node_count()))?;
-        let output = message.result.output?;
-        if !output.success() {
-            return Err(crate::Error::from("Build failed".to_string()));
-        }
-        completed[message.index.index()] = true;
-
-        for source in graph.neighbors_directed(message.index, EdgeDirection::Incoming) {
-            if is_ready(graph, &completed, source) {
-                tx_task
-                    .send(TaskMessage {
-                        index: source,
-                        task: graph.node_weight(source).unwrap().clone(),
-                    })
-                    .map_err(crate::Error::send_error)?;
+        let success = match message.result.output {
+            Ok(output) => output.success(),
+            Err(e) if !keep_going => return Err(e),
+            Err(_) => false,
+        };
+        if success {
+            completed[message.index.index()] = true;
+            if !matches!(message.task.action, BuildAction::Empty) {
+                scheduler
+                    .durations
+                    .insert(message.task.duration_key(), message.result.duration);
             }
+
+            for source in graph.neighbors_directed(message.index, EdgeDirection::Incoming) {
+                if is_ready(graph, &completed, source) {
+                    scheduler.push(source);
+                }
+            }
+        } else if keep_going {
+            failed.push(message.index);
+            skipped_count += skip_dependents(graph, &mut skipped, message.index);
+        } else {
+            return Err(crate::Error::from("Build failed".to_string()));
         }
+        scheduler.dispatch(graph, tx_task)?;
 
-        if *count == completed.len() {
-            return Ok(());
+        if *count + skipped_count == completed.len() {
+            if failed.is_empty() {
+                return Ok(());
+            }
+            let title = |index: NodeIndex| graph.node_weight(index).unwrap().title.clone();
+            return Err(crate::Error::BuildFailed {
+                failed: failed.into_iter().map(title).collect(),
+                skipped: graph
+                    .node_indices()
+                    .filter(|i| skipped[i.index()])
+                    .map(title)
+                    .collect(),
+            });
         }
     }
     Err(crate::Error::from(
@@ -217,6 +335,22 @@ where
     ))
 }
 
+// Mark all tasks depending on failed task as skipped, returns count of newly skipped tasks.
+fn skip_dependents(graph: &BuildGraph, skipped: &mut [bool], failed: NodeIndex) -> usize {
+    let mut count = 0;
+    let mut queue = vec![failed];
+    while let Some(index) = queue.pop() {
+        for source in graph.neighbors_directed(index, EdgeDirection::Incoming) {
+            if !skipped[source.index()] {
+                skipped[source.index()] = true;
+                count += 1;
+                queue.push(source);
+            }
+        }
+    }
+    count
+}
+
 fn is_ready<N, E>(graph: &Graph<N, E>, completed: &[bool], source: NodeIndex) -> bool {
     for neighbor in graph.neighbors_directed(source, EdgeDirection::Outgoing) {
         if !completed[neighbor.index()] {
@@ -230,6 +364,7 @@ pub fn execute_graph<F>(
     state: &SharedState,
     build_graph: BuildGraph,
     process_limit: usize,
+    keep_going: bool,
     update_progress: F,
 ) -> crate::Result<()>
 where
@@ -243,16 +378,35 @@ where
     let (tx_result, rx_result) = crossbeam_channel::unbounded::<ResultMessage>();
     let (tx_task, rx_task) = crossbeam_channel::unbounded::<TaskMessage>();
     let num_cpus = max(1, min(process_limit, graph.node_count()));
+    // Single task builds (octo_cl, octo_clang) have nothing to order and would only
+    // serialize on the durations file.
+    let track_durations = graph.node_count() > 1;
+    let durations = if track_durations {
+        state.task_durations.load().unwrap_or_else(|e| {
+            warn!("Can't load task durations: {}", e);
+            HashMap::new()
+        })
+    } else {
+        HashMap::new()
+    };
+    let mut scheduler = Scheduler::new(&graph, &durations, num_cpus);
     std::thread::scope(|scope| {
         for worker_id in 0..num_cpus {
             let local_rx_task = rx_task.clone();
             let local_tx_result = tx_result.clone();
             scope.spawn(move || {
+                let _lane = state
+                    .trace
+                    .as_ref()
+                    .map(|trace| trace.enter(worker_id, || format!("worker #{worker_id}")));
                 while let Ok(message) = local_rx_task.recv() {
+                    let span = trace::span(&message.task.title);
+                    let result = message.task.execute(state);
+                    drop(span);
                     match local_tx_result.send(ResultMessage {
                         index: message.index,
                         worker: worker_id,
-                        result: message.task.execute(state),
+                        result,
                         task: message.task,
                     }) {
                         Ok(_) => {}
@@ -266,8 +420,15 @@ where
         drop(tx_result);
         // Run all tasks.
         let mut count: usize = 0;
-        let result =
-            execute_until_failed(&graph, &tx_task, &rx_result, &mut count, &update_progress);
+        let result = execute_until_failed(
+            &graph,
+            &tx_task,
+            &rx_result,
+            &mut scheduler,
+            &mut count,
+            keep_going,
+            &update_progress,
+        );
         // Cleanup task queue.
         drop(tx_task);
         drop(rx_task);
@@ -275,23 +436,40 @@ where
         for message in rx_result {
             update_progress(&BuildResult::new(&message, &mut count, graph.node_count()))?;
         }
+        state.cache.flush_writes(&state.statistic);
+        if track_durations {
+            if let Err(e) = state.task_durations.save(&scheduler.durations) {
+                warn!("Can't save task durations: {}", e);
+            }
+        }
+        if let Some(trace) = &state.trace {
+            if let Err(e) = trace.write() {
+                warn!("Can't write build trace: {}", e);
+            }
+        }
+        state.write_statistic_json();
         result
     })
 }
 
 #[cfg(test)]
 mod test {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
     use std::sync::{Arc, Mutex};
+    use std::time::Duration;
 
-    use crate::compiler::SharedState;
+    use crate::compiler::{CommandArgs, CommandInfo, SharedState};
     use crate::config::Config;
-    use crate::worker::{execute_graph, BuildAction, BuildGraph, BuildTask};
+    use crate::worker::{
+        critical_paths, execute_graph, BuildAction, BuildGraph, BuildTask, Scheduler,
+    };
 
     #[test]
     fn test_execute_graph_empty() {
         let state = SharedState::new(&Config::default()).unwrap();
         let graph = BuildGraph::new();
-        execute_graph(&state, graph, 2, |_| {
+        execute_graph(&state, graph, 2, false, |_| {
             unreachable!();
         })
         .unwrap();
@@ -309,7 +487,7 @@ mod test {
         }));
 
         let result = Mutex::new(Vec::new());
-        execute_graph(&state, graph, 4, |r| {
+        execute_graph(&state, graph, 4, false, |r| {
             result.lock().unwrap().push(r.task.title.clone());
             Ok(())
         })
@@ -337,7 +515,7 @@ mod test {
         graph.add_edge(t2, t1, ());
 
         let result = Mutex::new(Vec::new());
-        execute_graph(&state, graph, 4, |r| {
+        execute_graph(&state, graph, 4, false, |r| {
             result.lock().unwrap().push(r.task.title.clone());
             Ok(())
         })
@@ -346,4 +524,92 @@ mod test {
         let actual: Vec<String> = result.lock().unwrap().clone();
         assert_eq!(actual, vec!["task 1".to_string(), "task 2".to_string()]);
     }
+
+    #[test]
+    fn test_execute_graph_keep_going() {
+        let state = SharedState::new(&Config::default()).unwrap();
+
+        // Task 1 fails, tasks 2 and 3 depend on it, task 5 depends on task 4
+        let mut graph = BuildGraph::new();
+        let tasks: Vec<_> = (1..=5)
+            .map(|i| {
+                let action = if i == 1 {
+                    BuildAction::Exec(
+                        CommandInfo::simple(PathBuf::from("octobuild-missing-command")),
+                        CommandArgs::Regular(Vec::new()),
+                    )
+                } else {
+                    BuildAction::Empty
+                };
+                graph.add_node(Arc::new(BuildTask {
+                    title: format!("task {i}"),
+                    action,
+                }))
+            })
+            .collect();
+        graph.add_edge(tasks[1], tasks[0], ());
+        graph.add_edge(tasks[2], tasks[1], ());
+        graph.add_edge(tasks[4], tasks[3], ());
+
+        let result = Mutex::new(Vec::new());
+        let error = execute_graph(&state, graph, 1, true, |r| {
+            result.lock().unwrap().push(r.task.title.clone());
+            Ok(())
+        })
+        .unwrap_err();
+
+        let mut actual: Vec<String> = result.lock().unwrap().clone();
+        actual.sort();
+        assert_eq!(actual, vec!["task 1", "task 4", "task 5"]);
+        match error {
+            crate::Error::BuildFailed { failed, skipped } => {
+                assert_eq!(failed, vec!["task 1"]);
+                assert_eq!(skipped, vec!["task 2", "task 3"]);
+            }
+            e => panic!("unexpected error: {e}"),
+        }
+    }
+
+    #[test]
+    fn test_critical_paths() {
+        let exec = |title: &str| {
+            Arc::new(BuildTask {
+                title: title.to_string(),
+                action: BuildAction::Exec(
+                    CommandInfo::simple(PathBuf::from("cc")),
+                    CommandArgs::Regular(vec![title.to_string()]),
+                ),
+            })
+        };
+        // Task c depends on b, task d depends on c
+        let mut graph = BuildGraph::new();
+        let a = graph.add_node(exec("a"));
+        let b = graph.add_node(exec("b"));
+        let c = graph.add_node(exec("c"));
+        let d = graph.add_node(Arc::new(BuildTask {
+            title: "d".to_string(),
+            action: BuildAction::Empty,
+        }));
+        graph.add_edge(c, b, ());
+        graph.add_edge(d, c, ());
+
+        // Task b was never built, so it takes average time
+        let durations = HashMap::from([
+            (graph[a].duration_key(), Duration::from_secs(5)),
+            (graph[c].duration_key(), Duration::from_secs(1)),
+        ]);
+        assert_eq!(
+            critical_paths(&graph, &durations),
+            vec![5000, 4000, 1000, 0]
+        );
+
+        let mut scheduler = Scheduler::new(&graph, &HashMap::new(), 1);
+        scheduler.push(a);
+        scheduler.push(b);
+        // Without history the longest chain of tasks goes first
+        let (tx_task, rx_task) = crossbeam_channel::unbounded();
+        scheduler.dispatch(&graph, &tx_task).unwrap();
+        assert_eq!(rx_task.try_recv().unwrap().index, b);
+        assert!(rx_task.try_recv().is_err());
+    }
 }